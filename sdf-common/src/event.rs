@@ -0,0 +1,184 @@
+/// Why the datapath dropped a packet, or flagged its source
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DropReason {
+    SourceBlacklist = 1,
+    PortBlacklist = 2,
+    /// The source crossed a scan detector threshold, the packet itself may pass
+    ScanDetected = 3,
+    /// The source was detected as scanning and is blocked for a while
+    PortScan = 4,
+    /// The packet was sent to a trap port, its source is now banned
+    TrapHit = 5,
+    /// The source hit a trap port earlier and is still banned
+    TrapBanned = 6,
+    /// The source matches a prefix of an external blocklist feed
+    FeedBlacklist = 7,
+    /// The source belongs to a blocked country or AS, `DropEvent::rule` tells which
+    GeoBlacklist = 8,
+}
+
+impl DropReason {
+    /// Reasons of dropped packets, `ScanDetected` only flags a source
+    pub const DROPS: [DropReason; 7] = [
+        Self::SourceBlacklist,
+        Self::PortBlacklist,
+        Self::PortScan,
+        Self::TrapHit,
+        Self::TrapBanned,
+        Self::FeedBlacklist,
+        Self::GeoBlacklist,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::SourceBlacklist),
+            2 => Some(Self::PortBlacklist),
+            3 => Some(Self::ScanDetected),
+            4 => Some(Self::PortScan),
+            5 => Some(Self::TrapHit),
+            6 => Some(Self::TrapBanned),
+            7 => Some(Self::FeedBlacklist),
+            8 => Some(Self::GeoBlacklist),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::SourceBlacklist => "source_blacklist",
+            Self::PortBlacklist => "port_blacklist",
+            Self::ScanDetected => "scan_detected",
+            Self::PortScan => "port_scan",
+            Self::TrapHit => "trap_hit",
+            Self::TrapBanned => "trap_banned",
+            Self::FeedBlacklist => "feed_blacklist",
+            Self::GeoBlacklist => "geo_blacklist",
+        }
+    }
+}
+
+/// Index of the passed packets in the PACKET_STATS per cpu array,
+/// dropped packets are counted at the index of their `DropReason`
+pub const PACKET_STATS_PASSED: u32 = 0;
+/// Size of the PACKET_STATS array, leaves room for new drop reasons
+pub const PACKET_STATS_LEN: u32 = 16;
+
+/// Index in PACKET_STATS of the drop events not sent because the CPU was over its budget
+pub const PACKET_STATS_EVENTS_SUPPRESSED: u32 = PACKET_STATS_LEN - 1;
+
+/// Drop events each CPU sends per second, on average
+pub const DROP_EVENT_RATE: u64 = 1000;
+/// Drop events a CPU can send at once after a quiet period
+pub const DROP_EVENT_BURST: u64 = 1000;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Token bucket limiting the drop events of one CPU, value of the EVENT_BUDGET per cpu array.
+///
+/// A flood would otherwise push one event per dropped packet through the perf buffer,
+/// when the datapath most needs to be cheap
+#[repr(C)]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct EventBudget {
+    pub tokens: u64,
+    /// Time the tokens were last refilled, from bpf_ktime_get_ns
+    pub refilled_ns: u64,
+}
+
+impl EventBudget {
+    /// Refill the bucket up to `now` and take a token, false when the event must be skipped
+    pub fn take(&mut self, now: u64) -> bool {
+        let refill = now
+            .saturating_sub(self.refilled_ns)
+            .saturating_mul(DROP_EVENT_RATE)
+            / NANOS_PER_SEC;
+        if refill > 0 {
+            if self.tokens.saturating_add(refill) >= DROP_EVENT_BURST {
+                self.tokens = DROP_EVENT_BURST;
+                self.refilled_ns = now;
+            } else {
+                self.tokens += refill;
+                // the time of a partial token counts towards the next one
+                self.refilled_ns += refill * NANOS_PER_SEC / DROP_EVENT_RATE;
+            }
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+/// Sent to userspace through the DROP_EVENTS perf array for every dropped packet, within
+/// the EventBudget of the CPU
+#[repr(C)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DropEvent {
+    pub source: u32,
+    pub dest: u32,
+    pub source_port: u16,
+    pub dest_port: u16,
+    pub reason: u8,
+    pub _padding: [u8; 3],
+    /// Index of the matched geo rule, only meaningful for `GeoBlacklist`
+    pub rule: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for DropEvent {}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        DropReason, EventBudget, DROP_EVENT_BURST, DROP_EVENT_RATE, PACKET_STATS_EVENTS_SUPPRESSED,
+        PACKET_STATS_LEN,
+    };
+
+    #[test]
+    fn convert_reason() {
+        for reason in [
+            DropReason::SourceBlacklist,
+            DropReason::PortBlacklist,
+            DropReason::ScanDetected,
+            DropReason::PortScan,
+            DropReason::TrapHit,
+            DropReason::TrapBanned,
+            DropReason::FeedBlacklist,
+            DropReason::GeoBlacklist,
+        ] {
+            assert!((reason as u32) < PACKET_STATS_LEN);
+            assert_eq!(DropReason::from_u8(reason as u8), Some(reason));
+        }
+        assert_eq!(DropReason::from_u8(0), None);
+        assert!(DropReason::DROPS
+            .iter()
+            .all(|reason| *reason as u32 != PACKET_STATS_EVENTS_SUPPRESSED));
+    }
+
+    #[test]
+    fn event_budget() {
+        let second = 1_000_000_000;
+        let mut budget = EventBudget::default();
+        let now = 100 * second;
+        let sent = (0..2 * DROP_EVENT_BURST)
+            .filter(|_| budget.take(now))
+            .count();
+        assert_eq!(sent as u64, DROP_EVENT_BURST);
+        assert!(!budget.take(now));
+
+        // one token per ms at 1000 events/s, the half token is kept
+        assert!(!budget.take(now + second / DROP_EVENT_RATE / 2));
+        assert!(budget.take(now + second / DROP_EVENT_RATE));
+        assert!(!budget.take(now + second / DROP_EVENT_RATE));
+        assert!(budget.take(now + 2 * second / DROP_EVENT_RATE));
+
+        // a long quiet period refills no more than the burst
+        let later = now + 3600 * second;
+        let sent = (0..2 * DROP_EVENT_BURST)
+            .filter(|_| budget.take(later))
+            .count();
+        assert_eq!(sent as u64, DROP_EVENT_BURST);
+    }
+}
@@ -0,0 +1,22 @@
+use crate::PACKET_STATS_LEN;
+
+/// Key of the per interface maps, a value scoped to the interface it was received on
+#[repr(C)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct IfaceKey {
+    pub ifindex: u32,
+    /// Source address in host order, port, or PACKET_STATS index in IFACE_STATS
+    pub value: u32,
+}
+
+impl IfaceKey {
+    pub const fn new(ifindex: u32, value: u32) -> Self {
+        Self { ifindex, value }
+    }
+}
+
+/// Entries of the IFACE_STATS per cpu map, room for 64 interfaces
+pub const IFACE_STATS_ENTRIES: u32 = 64 * PACKET_STATS_LEN;
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for IfaceKey {}
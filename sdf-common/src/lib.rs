@@ -1,7 +1,17 @@
 #![no_std]
 
+mod event;
+mod iface;
 mod ip_addr;
+mod limits;
+mod maps;
 mod port_range;
+mod scan;
 
+pub use event::*;
+pub use iface::*;
 pub use ip_addr::*;
+pub use limits::*;
+pub use maps::*;
 pub use port_range::*;
+pub use scan::*;
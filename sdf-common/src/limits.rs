@@ -0,0 +1,9 @@
+/// Default entries of the rule maps and of AUTO_WHITELIST and AUTO_BLACKLIST, resized at load time
+pub const RULE_MAP_ENTRIES: u32 = 4096;
+/// Entries of the TRAP_PORTS and GEO_PORTS maps
+pub const PORT_MAP_ENTRIES: u32 = 1024;
+/// Entries of XDP_CHAIN, the programs sdf_ingress tail-calls into
+pub const XDP_CHAIN_ENTRIES: u32 = 64;
+/// Layout of the pinned maps, bump it when a map changes key, value or meaning so an
+/// upgrade doesn't reuse maps the new programs can't read
+pub const MAP_LAYOUT_VERSION: u32 = 1;
@@ -0,0 +1,29 @@
+/// Maps of sdf-ebpf the daemon opens by name, an object given to `--bpf-object` must have
+/// every one of them. Maps only the programs use, like EVENT_BUDGET, aren't listed
+pub const MAP_NAMES: [&str; 25] = [
+    "SRC_BLACKLIST",
+    "SRC_WHITELIST",
+    "AUTO_WHITELIST",
+    "AUTO_BLACKLIST",
+    "PORT_BLACKLIST",
+    "IFACE_SRC_BLACKLIST",
+    "IFACE_SRC_WHITELIST",
+    "IFACE_PORT_BLACKLIST",
+    "FEED_BLACKLIST",
+    "GEO_BLACKLIST",
+    "GEO_PORTS",
+    "GEO_ALL_PORTS",
+    "BLOCKED_STATS",
+    "PACKET_STATS",
+    "IFACE_STATS",
+    "SOURCE_STATS",
+    "TRAP_PORTS",
+    "TRAP_BANS",
+    "SCAN_CONFIG",
+    "SCAN_STATE",
+    "DROP_EVENTS",
+    "L3_INTERFACES",
+    "XDP_CHAIN",
+    "XDP_CHAIN_SLOTS",
+    "MAP_LAYOUT",
+];
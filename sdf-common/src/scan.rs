@@ -0,0 +1,152 @@
+/// Bits of the distinct port and host bitmaps, thresholds above it can't be reached
+pub const SCAN_BITMAP_BITS: u32 = 256;
+
+/// What the datapath does with a source detected as scanning
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ScanAction {
+    /// Only send a `ScanDetected` event
+    Report = 1,
+    /// Also drop the source for `block_ns`
+    Drop = 2,
+}
+
+/// Settings of the scan detector, single entry of the SCAN_CONFIG array
+#[repr(C)]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct ScanConfig {
+    /// Length of one detection window, 0 disables the detector
+    pub window_ns: u64,
+    /// How long a detected source is dropped with `ScanAction::Drop`
+    pub block_ns: u64,
+    /// Distinct destination ports flagging a source, 0 to not count ports
+    pub max_ports: u32,
+    /// Distinct destination hosts flagging a source, 0 to not count hosts
+    pub max_hosts: u32,
+    pub action: u8,
+    pub _padding: [u8; 7],
+}
+
+impl ScanConfig {
+    pub fn is_scan(&self, state: &ScanState) -> bool {
+        (self.max_ports != 0 && state.distinct_ports() >= self.max_ports)
+            || (self.max_hosts != 0 && state.distinct_hosts() >= self.max_hosts)
+    }
+}
+
+/// Destinations touched by one source, value of the SCAN_STATE LRU map.
+///
+/// Ports and hosts are hashed into bitmaps, the number of set bits estimates how many
+/// distinct ones were seen. The previous window is kept so that counts slide over
+/// the last one to two windows instead of restarting from zero.
+#[repr(C)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ScanState {
+    /// Start of the current window, ns since boot
+    pub window_start: u64,
+    /// Packets from the source are dropped until this time with `ScanAction::Drop`
+    pub blocked_until: u64,
+    pub ports: [u64; 4],
+    pub previous_ports: [u64; 4],
+    pub hosts: [u64; 4],
+    pub previous_hosts: [u64; 4],
+}
+
+impl ScanState {
+    pub fn new(now: u64) -> Self {
+        Self {
+            window_start: now,
+            blocked_until: 0,
+            ports: [0; 4],
+            previous_ports: [0; 4],
+            hosts: [0; 4],
+            previous_hosts: [0; 4],
+        }
+    }
+
+    /// Start a new window once the current one is over
+    pub fn roll(&mut self, now: u64, window_ns: u64) {
+        let elapsed = now.wrapping_sub(self.window_start);
+        if elapsed < window_ns {
+            return;
+        }
+        if elapsed < 2 * window_ns {
+            self.previous_ports = self.ports;
+            self.previous_hosts = self.hosts;
+        } else {
+            self.previous_ports = [0; 4];
+            self.previous_hosts = [0; 4];
+        }
+        self.ports = [0; 4];
+        self.hosts = [0; 4];
+        self.window_start = now;
+    }
+
+    pub fn record(&mut self, dest: u32, dest_port: u16) {
+        set_bit(&mut self.ports, (dest_port ^ (dest_port >> 8)) as u32);
+        set_bit(
+            &mut self.hosts,
+            dest ^ (dest >> 8) ^ (dest >> 16) ^ (dest >> 24),
+        );
+    }
+
+    pub fn distinct_ports(&self) -> u32 {
+        count_bits(&self.ports, &self.previous_ports)
+    }
+
+    pub fn distinct_hosts(&self) -> u32 {
+        count_bits(&self.hosts, &self.previous_hosts)
+    }
+}
+
+fn set_bit(bitmap: &mut [u64; 4], slot: u32) {
+    bitmap[((slot >> 6) & 3) as usize] |= 1 << (slot & 63);
+}
+
+fn count_bits(current: &[u64; 4], previous: &[u64; 4]) -> u32 {
+    (current[0] | previous[0]).count_ones()
+        + (current[1] | previous[1]).count_ones()
+        + (current[2] | previous[2]).count_ones()
+        + (current[3] | previous[3]).count_ones()
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ScanConfig {}
+
+#[cfg(test)]
+mod test {
+    use crate::{ScanConfig, ScanState};
+
+    #[test]
+    fn count_distinct() {
+        let mut state = ScanState::new(0);
+        for port in 1..=20 {
+            state.record(0x0a000001, port);
+            state.record(0x0a000001, port);
+        }
+        assert_eq!(state.distinct_ports(), 20);
+        assert_eq!(state.distinct_hosts(), 1);
+
+        let config = ScanConfig {
+            window_ns: 10,
+            max_ports: 20,
+            ..Default::default()
+        };
+        assert!(config.is_scan(&state));
+    }
+
+    #[test]
+    fn slide_window() {
+        let mut state = ScanState::new(0);
+        state.record(0x0a000001, 22);
+        state.roll(15, 10);
+        state.record(0x0a000001, 23);
+        // the previous window still counts
+        assert_eq!(state.distinct_ports(), 2);
+
+        state.roll(30, 10);
+        assert_eq!(state.distinct_ports(), 1);
+        state.roll(100, 10);
+        assert_eq!(state.distinct_ports(), 0);
+    }
+}
@@ -1,9 +1,13 @@
 #![no_std]
 #![no_main]
 
-use aya_bpf::{bindings::xdp_action, macros::{xdp, classifier, map}, programs::{XdpContext, TcContext}, maps::HashMap};
+use aya_bpf::{bindings::{xdp_action, BPF_F_NO_PREALLOC, TC_ACT_OK, TC_ACT_SHOT}, helpers::bpf_ktime_get_ns, macros::{xdp, classifier, map}, programs::{XdpContext, TcContext}, maps::{lpm_trie::Key, Array, HashMap, LpmTrie, LruHashMap, PerCpuArray, PerCpuHashMap, PerfEventArray, ProgramArray}, BpfContext};
 use aya_log_ebpf::{info, error};
-use network_types::{eth::{EthHdr, EtherType}, ip::{Ipv4Hdr, IpProto}, udp::UdpHdr};
+use network_types::{eth::{EthHdr, EtherType}, ip::{Ipv4Hdr, IpProto}, tcp::TcpHdr, udp::UdpHdr};
+use sdf_common::{
+    DropEvent, DropReason, EventBudget, IfaceKey, ScanAction, ScanConfig, ScanState, IFACE_STATS_ENTRIES,
+    PACKET_STATS_EVENTS_SUPPRESSED, PACKET_STATS_LEN, PACKET_STATS_PASSED, PORT_MAP_ENTRIES, RULE_MAP_ENTRIES, XDP_CHAIN_ENTRIES,
+};
 
 use crate::parse::{ptr_at, tc_ptr_at};
 
@@ -11,23 +15,149 @@ const ETH_IP_V4_TYPE: u16 = 0x0800_u16;
 
 mod parse;
 
+// the maps userspace opens by name are listed in MAP_NAMES of sdf-common
 #[map]
-static SRC_BLACKLIST: HashMap<u32, u8> = HashMap::<u32, u8>::with_max_entries(4096, 0);
+static SRC_BLACKLIST: HashMap<u32, u8> = HashMap::<u32, u8>::with_max_entries(RULE_MAP_ENTRIES, 0);
 
 #[map]
-static SRC_WHITELIST: HashMap<u32, u8> = HashMap::<u32, u8>::with_max_entries(4096, 0);
+static SRC_WHITELIST: HashMap<u32, u8> = HashMap::<u32, u8>::with_max_entries(RULE_MAP_ENTRIES, 0);
 
+/// Destinations of outgoing traffic to blacklisted ports, the oldest are evicted when full
 #[map]
-static PORT_BLACKLIST: HashMap<u16, u8> = HashMap::<u16, u8>::with_max_entries(4096, 0);
+static AUTO_WHITELIST: LruHashMap<u32, u8> = LruHashMap::<u32, u8>::with_max_entries(RULE_MAP_ENTRIES, 0);
+
+/// Sources banned by the auto ban detectors, the oldest are evicted when full
+#[map]
+static AUTO_BLACKLIST: LruHashMap<u32, u8> = LruHashMap::<u32, u8>::with_max_entries(RULE_MAP_ENTRIES, 0);
+
+#[map]
+static PORT_BLACKLIST: HashMap<u16, u8> = HashMap::<u16, u8>::with_max_entries(RULE_MAP_ENTRIES, 0);
+
+/// Rules of a single interface, checked after the global rules of the same kind
+#[map]
+static IFACE_SRC_BLACKLIST: HashMap<IfaceKey, u8> = HashMap::<IfaceKey, u8>::with_max_entries(RULE_MAP_ENTRIES, 0);
+
+#[map]
+static IFACE_SRC_WHITELIST: HashMap<IfaceKey, u8> = HashMap::<IfaceKey, u8>::with_max_entries(RULE_MAP_ENTRIES, 0);
+
+#[map]
+static IFACE_PORT_BLACKLIST: HashMap<IfaceKey, u8> = HashMap::<IfaceKey, u8>::with_max_entries(RULE_MAP_ENTRIES, 0);
+
+/// Prefixes of the external blocklist feeds, keys are in network byte order
+#[map]
+static FEED_BLACKLIST: LpmTrie<u32, u8> = LpmTrie::<u32, u8>::with_max_entries(1 << 16, BPF_F_NO_PREALLOC);
+
+/// Prefixes of the blocked countries and ASNs to the bitmask of the geo rules they match,
+/// keys are in network byte order
+#[map]
+static GEO_BLACKLIST: LpmTrie<u32, u64> = LpmTrie::<u32, u64>::with_max_entries(1 << 18, BPF_F_NO_PREALLOC);
+
+/// Service port to the bitmask of the geo rules limited to it
+#[map]
+static GEO_PORTS: HashMap<u16, u64> = HashMap::<u16, u64>::with_max_entries(PORT_MAP_ENTRIES, 0);
+
+/// Bitmask of the geo rules applying to every port
+#[map]
+static GEO_ALL_PORTS: Array<u64> = Array::<u64>::with_max_entries(1, 0);
 
 #[map]
 static BLOCKED_STATS: HashMap<u16, u64> = HashMap::<u16, u64>::with_max_entries(1 << 16, 0);
 
+#[map]
+static PACKET_STATS: PerCpuArray<u64> = PerCpuArray::<u64>::with_max_entries(PACKET_STATS_LEN, 0);
+
+/// PACKET_STATS broken down by the interface the packet was received on
+#[map]
+static IFACE_STATS: PerCpuHashMap<IfaceKey, u64> = PerCpuHashMap::<IfaceKey, u64>::with_max_entries(IFACE_STATS_ENTRIES, 0);
+
+#[map]
+static SOURCE_STATS: LruHashMap<u32, u64> = LruHashMap::<u32, u64>::with_max_entries(1 << 14, 0);
+
+/// Trap port to the ban duration of the sources hitting it, in ns
+#[map]
+static TRAP_PORTS: HashMap<u16, u64> = HashMap::<u16, u64>::with_max_entries(PORT_MAP_ENTRIES, 0);
+
+/// Source banned by a trap port to the end of its ban, ns since boot
+#[map]
+static TRAP_BANS: LruHashMap<u32, u64> = LruHashMap::<u32, u64>::with_max_entries(1 << 14, 0);
+
+#[map]
+static SCAN_CONFIG: Array<ScanConfig> = Array::<ScanConfig>::with_max_entries(1, 0);
+
+#[map]
+static SCAN_STATE: LruHashMap<u32, ScanState> = LruHashMap::<u32, ScanState>::with_max_entries(1 << 14, 0);
+
+#[map]
+static DROP_EVENTS: PerfEventArray<DropEvent> = PerfEventArray::<DropEvent>::new(0);
+
+#[map]
+static EVENT_BUDGET: PerCpuArray<EventBudget> = PerCpuArray::<EventBudget>::with_max_entries(1, 0);
+
+/// Interfaces without an Ethernet header, like tunnels, their packets start at the IP header
+#[map]
+static L3_INTERFACES: HashMap<u32, u8> = HashMap::<u32, u8>::with_max_entries(64, 0);
+
+/// XDP programs of other objects, tail-called when sdf_ingress passes a packet
+#[map]
+static XDP_CHAIN: ProgramArray = ProgramArray::with_max_entries(XDP_CHAIN_ENTRIES, 0);
+
+/// Index in XDP_CHAIN of the program chained on an interface
+#[map]
+static XDP_CHAIN_SLOTS: HashMap<u32, u32> = HashMap::<u32, u32>::with_max_entries(XDP_CHAIN_ENTRIES, 0);
+
+/// MAP_LAYOUT_VERSION of the userspace that created the pinned maps, only read by userspace
+#[map]
+static MAP_LAYOUT: Array<u32> = Array::<u32>::with_max_entries(1, 0);
+
+/// What the rules look at in an IPv4 TCP or UDP packet
+struct Packet {
+    ifindex: u32,
+    source: u32,
+    dest: u32,
+    source_port: u16,
+    dest_port: u16,
+    proto: IpProto,
+}
+
+enum Verdict {
+    Pass,
+    Drop,
+}
+
 #[xdp]
 pub fn sdf_ingress(ctx: XdpContext) -> u32 {
-    match try_sdf_ingress(ctx) {
-        Ok(ret) => ret,
-        Err(_) => xdp_action::XDP_ABORTED,
+    let ifindex = unsafe { (*ctx.ctx).ingress_ifindex };
+    let verdict = match parse_xdp(&ctx, ifindex) {
+        Ok(Some(packet)) => evaluate(&ctx, &packet),
+        Ok(None) => Verdict::Pass,
+        Err(_) => return xdp_action::XDP_ABORTED,
+    };
+    match verdict {
+        Verdict::Pass => {
+            count_packet(&ctx, ifindex, PACKET_STATS_PASSED);
+            chain(&ctx, ifindex);
+            xdp_action::XDP_PASS
+        },
+        Verdict::Drop => xdp_action::XDP_DROP,
+    }
+}
+
+/// Same rules as sdf_ingress, for the interfaces where XDP can't run
+#[classifier]
+pub fn sdf_tc_ingress(ctx: TcContext) -> i32 {
+    let ifindex = unsafe { (*ctx.skb.skb).ingress_ifindex };
+    let verdict = match parse_tc(&ctx, ifindex) {
+        Ok(Some(packet)) => evaluate(&ctx, &packet),
+        Ok(None) => Verdict::Pass,
+        // dropped like XDP_ABORTED does
+        Err(_) => return TC_ACT_SHOT as i32,
+    };
+    match verdict {
+        Verdict::Pass => {
+            count_packet(&ctx, ifindex, PACKET_STATS_PASSED);
+            TC_ACT_OK as i32
+        },
+        Verdict::Drop => TC_ACT_SHOT as i32,
     }
 }
 
@@ -39,7 +169,15 @@ pub fn sdf_egress(ctx: TcContext) -> i32 {
     }
 }
 
-fn increase_drop(ctx: &XdpContext, map: &HashMap<u16, u64>, port: u16) {
+/// Hand the packet to the program chained on the interface, its verdict is the final one.
+/// Only returns when there is none
+fn chain(ctx: &XdpContext, ifindex: u32) {
+    if let Some(slot) = unsafe { XDP_CHAIN_SLOTS.get(&ifindex) } {
+        let _ = unsafe { XDP_CHAIN.tail_call(ctx, *slot) };
+    }
+}
+
+fn increase_drop<C: BpfContext>(ctx: &C, map: &HashMap<u16, u64>, port: u16) {
     if let Some(slot) = map.get_ptr_mut(&port) {
         unsafe { *slot += 1 };
     } else {
@@ -49,67 +187,296 @@ fn increase_drop(ctx: &XdpContext, map: &HashMap<u16, u64>, port: u16) {
     }
 }
 
-fn allow_port(_ctx: &XdpContext, blacklist: &HashMap<u16, u8>, port: u16) -> bool {
-    unsafe { blacklist.get(&port).is_none() }
+fn count_packet<C: BpfContext>(ctx: &C, ifindex: u32, index: u32) {
+    if let Some(slot) = PACKET_STATS.get_ptr_mut(index) {
+        unsafe { *slot += 1 };
+    }
+    let key = IfaceKey::new(ifindex, index);
+    if let Some(slot) = IFACE_STATS.get_ptr_mut(&key) {
+        unsafe { *slot += 1 };
+    } else {
+        if let Err(e) = IFACE_STATS.insert(&key, &1, 0) {
+            error!(ctx, "add interface {} to IFACE_STATS error {}", ifindex, e);
+        }
+    }
+}
+
+fn increase_source_drop<C: BpfContext>(ctx: &C, source: u32) {
+    if let Some(slot) = SOURCE_STATS.get_ptr_mut(&source) {
+        unsafe { *slot += 1 };
+    } else {
+        if let Err(e) = SOURCE_STATS.insert(&source, &1, 0) {
+            error!(ctx, "add source {:x} to SOURCE_STATS error {}", source, e);
+        }
+    }
+}
+
+fn report_drop<C: BpfContext>(ctx: &C, packet: &Packet, reason: DropReason) {
+    report_rule_drop(ctx, packet, reason, 0);
+}
+
+fn report_rule_drop<C: BpfContext>(ctx: &C, packet: &Packet, reason: DropReason, rule: u32) {
+    count_packet(ctx, packet.ifindex, reason as u32);
+    increase_source_drop(ctx, packet.source);
+    report_event(ctx, packet, reason, rule);
+}
+
+fn report_event<C: BpfContext>(ctx: &C, packet: &Packet, reason: DropReason, rule: u32) {
+    // scan detections are already sent once per source and window
+    if reason != DropReason::ScanDetected && !take_event_budget() {
+        count_packet(ctx, packet.ifindex, PACKET_STATS_EVENTS_SUPPRESSED);
+        return;
+    }
+    let event = DropEvent {
+        source: packet.source,
+        dest: packet.dest,
+        source_port: packet.source_port,
+        dest_port: packet.dest_port,
+        reason: reason as u8,
+        _padding: [0; 3],
+        rule,
+    };
+    DROP_EVENTS.output(ctx, &event, 0);
+}
+
+fn take_event_budget() -> bool {
+    match EVENT_BUDGET.get_ptr_mut(0) {
+        Some(budget) => unsafe { (*budget).take(bpf_ktime_get_ns()) },
+        None => false,
+    }
+}
+
+/// Index of the first geo rule matching the source on this service port
+fn check_geo(source: u32, dest_port: u16) -> Option<u32> {
+    let rules = *GEO_BLACKLIST.get(&Key::new(32, source.to_be()))?;
+    let mut enabled = GEO_ALL_PORTS.get(0).copied().unwrap_or(0);
+    if let Some(mask) = unsafe { GEO_PORTS.get(&dest_port) } {
+        enabled |= *mask;
+    }
+    let matched = rules & enabled;
+    if matched == 0 {
+        return None;
+    }
+    Some(matched.trailing_zeros())
+}
+
+/// Drop sources still banned by a trap port, ban the ones hitting a trap port now
+fn check_trap<C: BpfContext>(ctx: &C, source: u32, dest_port: u16) -> Option<DropReason> {
+    let now = unsafe { bpf_ktime_get_ns() };
+    if let Some(expires) = unsafe { TRAP_BANS.get(&source) } {
+        if *expires > now {
+            return Some(DropReason::TrapBanned);
+        }
+        let _ = TRAP_BANS.remove(&source);
+    }
+
+    let ttl = unsafe { TRAP_PORTS.get(&dest_port) }?;
+    if let Err(e) = TRAP_BANS.insert(&source, &(now + *ttl), 0) {
+        error!(ctx, "ban {:x} from trap port {} error {}", source, dest_port, e);
+    }
+    Some(DropReason::TrapHit)
 }
 
-fn try_sdf_ingress(ctx: XdpContext) -> Result<u32, ()> {
-    let ethhdr: *const EthHdr = unsafe { ptr_at(&ctx, 0)? };
-    match unsafe { (*ethhdr).ether_type } {
-        EtherType::Ipv4 => {}
-        _ => return Ok(xdp_action::XDP_PASS),
+/// Track the destinations of the source, returns true when it must be dropped as a scanner
+fn check_scan<C: BpfContext>(ctx: &C, packet: &Packet) -> bool {
+    let config = match SCAN_CONFIG.get(0) {
+        Some(config) if config.window_ns != 0 => config,
+        _ => return false,
+    };
+    let now = unsafe { bpf_ktime_get_ns() };
+    let source = packet.source;
+
+    let state = match SCAN_STATE.get_ptr_mut(&source) {
+        Some(state) => unsafe { &mut *state },
+        None => {
+            if let Err(e) = SCAN_STATE.insert(&source, &ScanState::new(now), 0) {
+                error!(ctx, "add source {:x} to SCAN_STATE error {}", source, e);
+                return false;
+            }
+            match SCAN_STATE.get_ptr_mut(&source) {
+                Some(state) => unsafe { &mut *state },
+                None => return false,
+            }
+        }
+    };
+
+    let drop = config.action == ScanAction::Drop as u8;
+    if drop && state.blocked_until > now {
+        return true;
     }
 
-    let ipv4hdr: *const Ipv4Hdr = unsafe { ptr_at(&ctx, EthHdr::LEN)? };
+    state.roll(now, config.window_ns);
+    let flagged = config.is_scan(state);
+    state.record(packet.dest, packet.dest_port);
+    if !flagged && config.is_scan(state) {
+        state.blocked_until = now + config.block_ns;
+        count_packet(ctx, packet.ifindex, DropReason::ScanDetected as u32);
+        report_event(ctx, packet, DropReason::ScanDetected, 0);
+        return drop;
+    }
+    false
+}
+
+fn allow_port(ifindex: u32, blacklist: &HashMap<u16, u8>, port: u16) -> bool {
+    let key = IfaceKey::new(ifindex, port as u32);
+    unsafe { blacklist.get(&port).is_none() && IFACE_PORT_BLACKLIST.get(&key).is_none() }
+}
+
+fn has_ethernet(ifindex: u32) -> bool {
+    unsafe { L3_INTERFACES.get(&ifindex).is_none() }
+}
+
+/// None for the packets the rules don't apply to, passed without a look
+fn parse_xdp(ctx: &XdpContext, ifindex: u32) -> Result<Option<Packet>, ()> {
+    let offset = if has_ethernet(ifindex) {
+        let ethhdr: *const EthHdr = unsafe { ptr_at(ctx, 0)? };
+        match unsafe { (*ethhdr).ether_type } {
+            EtherType::Ipv4 => {}
+            _ => return Ok(None),
+        }
+        EthHdr::LEN
+    } else {
+        let version: *const u8 = unsafe { ptr_at(ctx, 0)? };
+        if unsafe { *version } >> 4 != 4 {
+            return Ok(None);
+        }
+        0
+    };
+
+    let ipv4hdr: *const Ipv4Hdr = unsafe { ptr_at(ctx, offset)? };
     let source = u32::from_be(unsafe { (*ipv4hdr).src_addr });
-    // let dest = u32::from_be(unsafe { (*ipv4hdr).dst_addr });
+    let dest = u32::from_be(unsafe { (*ipv4hdr).dst_addr });
 
-    let (source_port, _dest_port) = unsafe { 
-        match (*ipv4hdr).proto {
+    let proto = unsafe { (*ipv4hdr).proto };
+    let (source_port, dest_port) = unsafe { 
+        match proto {
             IpProto::Udp => {
-                let udphdr: *const UdpHdr = ptr_at(&ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
+                let udphdr: *const UdpHdr = ptr_at(ctx, offset + Ipv4Hdr::LEN)?;
                 ((*udphdr).source.to_be(), (*udphdr).dest.to_be())
             },
-            // IpProto::Tcp => {
-            //     let tcphdr: *const TcpHdr = ptr_at(&ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
-            //     ((*tcphdr).source.to_be(), (*tcphdr).dest.to_be())
-            // }
-            _ => return Ok(xdp_action::XDP_PASS)
+            IpProto::Tcp => {
+                let tcphdr: *const TcpHdr = ptr_at(ctx, offset + Ipv4Hdr::LEN)?;
+                ((*tcphdr).source.to_be(), (*tcphdr).dest.to_be())
+            }
+            _ => return Ok(None)
+        }
+    };
+    Ok(Some(Packet { ifindex, source, dest, source_port, dest_port, proto }))
+}
+
+/// Same as parse_xdp, the socket buffer is read through bpf_skb_load_bytes
+fn parse_tc(ctx: &TcContext, ifindex: u32) -> Result<Option<Packet>, ()> {
+    let offset = if has_ethernet(ifindex) {
+        let ether_type: u16 = ctx.load(12).map_err(|_| ())?;
+        if u16::from_be(ether_type) != ETH_IP_V4_TYPE {
+            return Ok(None);
+        }
+        EthHdr::LEN
+    } else {
+        let version: u8 = ctx.load(0).map_err(|_| ())?;
+        if version >> 4 != 4 {
+            return Ok(None);
+        }
+        0
+    };
+
+    let ipv4hdr: Ipv4Hdr = ctx.load(offset).map_err(|_| ())?;
+    let source = u32::from_be(ipv4hdr.src_addr);
+    let dest = u32::from_be(ipv4hdr.dst_addr);
+
+    let proto = ipv4hdr.proto;
+    let (source_port, dest_port) = match proto {
+        IpProto::Udp => {
+            let udphdr: UdpHdr = ctx.load(offset + Ipv4Hdr::LEN).map_err(|_| ())?;
+            (udphdr.source.to_be(), udphdr.dest.to_be())
+        },
+        IpProto::Tcp => {
+            let tcphdr: TcpHdr = ctx.load(offset + Ipv4Hdr::LEN).map_err(|_| ())?;
+            (tcphdr.source.to_be(), tcphdr.dest.to_be())
         }
+        _ => return Ok(None)
     };
+    Ok(Some(Packet { ifindex, source, dest, source_port, dest_port, proto }))
+}
+
+/// The rules, in order, shared by the XDP and TC ingress programs
+fn evaluate<C: BpfContext>(ctx: &C, packet: &Packet) -> Verdict {
+    let source = packet.source;
+    let iface_source = IfaceKey::new(packet.ifindex, source);
+    if unsafe {
+        SRC_WHITELIST.get(&source).is_some()
+            || IFACE_SRC_WHITELIST.get(&iface_source).is_some()
+            || AUTO_WHITELIST.get(&source).is_some()
+    } {
+        return Verdict::Pass;
+    }
+
+    // the manual source and port blacklists have always been UDP only
+    let udp = matches!(packet.proto, IpProto::Udp);
+    if unsafe {
+        (udp
+            && (SRC_BLACKLIST.get(&source).is_some()
+                || IFACE_SRC_BLACKLIST.get(&iface_source).is_some()))
+            || AUTO_BLACKLIST.get(&source).is_some()
+    } {
+        report_drop(ctx, packet, DropReason::SourceBlacklist);
+        return Verdict::Drop;
+    }
+
+    if FEED_BLACKLIST.get(&Key::new(32, source.to_be())).is_some() {
+        report_drop(ctx, packet, DropReason::FeedBlacklist);
+        return Verdict::Drop;
+    }
 
-    if unsafe { SRC_WHITELIST.get(&source).is_some() } {
-        return Ok(xdp_action::XDP_PASS);
+    if let Some(rule) = check_geo(source, packet.dest_port) {
+        report_rule_drop(ctx, packet, DropReason::GeoBlacklist, rule);
+        return Verdict::Drop;
     }
 
-    if unsafe { SRC_BLACKLIST.get(&source).is_some() } {
-        return Ok(xdp_action::XDP_DROP);
+    if let Some(reason) = check_trap(ctx, source, packet.dest_port) {
+        report_drop(ctx, packet, reason);
+        return Verdict::Drop;
     }
 
-    if !allow_port(&ctx, &PORT_BLACKLIST, source_port) {
-        increase_drop(&ctx, &BLOCKED_STATS, source_port);
-        return Ok(xdp_action::XDP_DROP)
+    if check_scan(ctx, packet) {
+        report_drop(ctx, packet, DropReason::PortScan);
+        return Verdict::Drop;
     }
 
-    Ok(xdp_action::XDP_PASS)
+    // reflection defense, only for UDP
+    if udp && !allow_port(packet.ifindex, &PORT_BLACKLIST, packet.source_port) {
+        increase_drop(ctx, &BLOCKED_STATS, packet.source_port);
+        report_drop(ctx, packet, DropReason::PortBlacklist);
+        return Verdict::Drop;
+    }
+
+    Verdict::Pass
 }
 
 fn try_sdf_egress(ctx: TcContext) -> Result<i32, i32> {
     let mut buf: [u8; 4] = [0; 4];
-    unsafe { tc_ptr_at(&ctx, 12, &mut buf[0..2])? };
-
-    if buf[0] != (ETH_IP_V4_TYPE >> 8) as u8 || buf[1] != 0 {
-        return Ok(1);
-    }
+    let offset = if has_ethernet(unsafe { (*ctx.skb.skb).ifindex }) {
+        unsafe { tc_ptr_at(&ctx, 12, &mut buf[0..2])? };
+        if buf[0] != (ETH_IP_V4_TYPE >> 8) as u8 || buf[1] != 0 {
+            return Ok(1);
+        }
+        EthHdr::LEN
+    } else {
+        unsafe { tc_ptr_at(&ctx, 0, &mut buf[0..1])? };
+        if buf[0] >> 4 != 4 {
+            return Ok(1);
+        }
+        0
+    };
 
-    unsafe { tc_ptr_at(&ctx, EthHdr::LEN + Ipv4Hdr::LEN, &mut buf[0..4])? };
+    unsafe { tc_ptr_at(&ctx, offset + Ipv4Hdr::LEN, &mut buf[0..4])? };
 
     let dest_port = (buf[2] as u16) << 8 | buf[3] as u16;
     if unsafe { PORT_BLACKLIST.get(&dest_port).is_some() } {
-        unsafe { tc_ptr_at(&ctx, EthHdr::LEN + Ipv4Hdr::LEN - 4, &mut buf[0..4])? };
+        unsafe { tc_ptr_at(&ctx, offset + Ipv4Hdr::LEN - 4, &mut buf[0..4])? };
         let dest_ip = u32::from_be_bytes(buf);
-        if unsafe { SRC_WHITELIST.get(&dest_ip).is_none() } {
-            if let Err(e) = SRC_WHITELIST.insert(&dest_ip, &0, 0) {
+        if unsafe { SRC_WHITELIST.get(&dest_ip).is_none() && AUTO_WHITELIST.get(&dest_ip).is_none() } {
+            if let Err(e) = AUTO_WHITELIST.insert(&dest_ip, &0, 0) {
                 error!(&ctx, "add {:x}:{} to whitelist error {}", dest_ip, dest_port, e);
             } else {
                 info!(&ctx, "auto added {:x}:{} to whitelist", dest_ip, dest_port);
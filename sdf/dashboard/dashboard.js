@@ -0,0 +1,183 @@
+// Dashboard over the /v1 control API, everything is polled from the browser
+const POLL_MS = 2000;
+const HISTORY = 90;
+const MAX_EVENTS = 50;
+const TOP = 10;
+
+const tokenInput = document.getElementById('token');
+tokenInput.value = localStorage.getItem('sdf-token') || '';
+tokenInput.addEventListener('change', () => {
+  localStorage.setItem('sdf-token', tokenInput.value);
+  refresh();
+});
+
+async function api(method, path) {
+  const headers = {};
+  if (tokenInput.value) {
+    headers['Authorization'] = 'Bearer ' + tokenInput.value;
+  }
+  const res = await fetch('/v1/' + path, { method, headers });
+  const body = await res.json();
+  if (!body.status) {
+    throw new Error(body.error || res.statusText);
+  }
+  return body.data;
+}
+
+function setStatus(error) {
+  const status = document.getElementById('status');
+  status.textContent = error ? error.message : '';
+  status.className = error ? 'error' : '';
+}
+
+// Rates are computed from the difference between two counter samples
+let lastSample = null;
+const rates = [];
+
+function updateRates(stats) {
+  const now = Date.now();
+  if (lastSample) {
+    const seconds = (now - lastSample.time) / 1000;
+    rates.push({
+      passed: Math.max(0, stats.passed - lastSample.passed) / seconds,
+      dropped: Math.max(0, stats.dropped - lastSample.dropped) / seconds,
+    });
+    if (rates.length > HISTORY) {
+      rates.shift();
+    }
+  }
+  lastSample = { time: now, passed: stats.passed, dropped: stats.dropped };
+
+  const last = rates[rates.length - 1];
+  document.getElementById('pass-rate').textContent = last ? last.passed.toFixed(1) : '-';
+  document.getElementById('drop-rate').textContent = last ? last.dropped.toFixed(1) : '-';
+  drawRates();
+}
+
+function drawRates() {
+  const canvas = document.getElementById('rates');
+  canvas.width = canvas.clientWidth;
+  const ctx = canvas.getContext('2d');
+  ctx.clearRect(0, 0, canvas.width, canvas.height);
+
+  const max = Math.max(1, ...rates.map((r) => Math.max(r.passed, r.dropped)));
+  const step = canvas.width / (HISTORY - 1);
+  for (const [key, color] of [['passed', '#2e7d32'], ['dropped', '#c62828']]) {
+    ctx.strokeStyle = color;
+    ctx.lineWidth = 2;
+    ctx.beginPath();
+    rates.forEach((rate, i) => {
+      const x = (HISTORY - rates.length + i) * step;
+      const y = canvas.height - (rate[key] / max) * (canvas.height - 10);
+      i === 0 ? ctx.moveTo(x, y) : ctx.lineTo(x, y);
+    });
+    ctx.stroke();
+  }
+  ctx.fillStyle = '#666';
+  ctx.fillText(max.toFixed(1) + '/s', 4, 12);
+}
+
+function drawBars(id, rows) {
+  const el = document.getElementById(id);
+  el.replaceChildren();
+  const max = Math.max(1, ...rows.map((row) => row[1]));
+  for (const [label, count] of rows) {
+    const row = document.createElement('div');
+    row.className = 'row';
+    const name = document.createElement('span');
+    name.className = 'label';
+    name.textContent = label;
+    const bar = document.createElement('span');
+    bar.className = 'bar';
+    bar.style.width = (count / max) * 60 + '%';
+    const value = document.createElement('span');
+    value.textContent = count;
+    row.append(name, bar, value);
+    el.append(row);
+  }
+}
+
+function drawRules(rules) {
+  const kinds = {
+    source_blacklist: 'blacklist/source',
+    source_whitelist: 'whitelist/source',
+    port_blacklist: 'blacklist/port',
+  };
+  for (const [key, path] of Object.entries(kinds)) {
+    const list = document.getElementById(key);
+    list.replaceChildren();
+    for (const value of rules[key]) {
+      const item = document.createElement('li');
+      item.textContent = value;
+      const remove = document.createElement('button');
+      remove.textContent = 'remove';
+      remove.addEventListener('click', () => changeRule('DELETE', path, value));
+      item.append(remove);
+      list.append(item);
+    }
+  }
+}
+
+async function changeRule(method, path, value) {
+  try {
+    await api(method, 'rules/' + path + '/' + encodeURIComponent(value));
+    drawRules(await api('GET', 'rules'));
+    setStatus(null);
+  } catch (e) {
+    setStatus(e);
+  }
+}
+
+document.getElementById('add-rule').addEventListener('submit', (e) => {
+  e.preventDefault();
+  const value = document.getElementById('rule-value');
+  changeRule('PUT', document.getElementById('rule-kind').value, value.value.trim());
+  value.value = '';
+});
+
+let lastSeq = 0;
+
+function addEvents(events) {
+  const body = document.getElementById('events');
+  for (const event of events) {
+    lastSeq = Math.max(lastSeq, event.seq);
+    const row = document.createElement('tr');
+    for (const text of [
+      event.timestamp,
+      event.source + ':' + event.source_port,
+      event.dest + ':' + event.dest_port,
+      event.matched ? event.reason + ' ' + event.matched : event.reason,
+    ]) {
+      const cell = document.createElement('td');
+      cell.textContent = text;
+      row.append(cell);
+    }
+    body.prepend(row);
+  }
+  while (body.children.length > MAX_EVENTS) {
+    body.lastChild.remove();
+  }
+}
+
+async function refresh() {
+  try {
+    const [packets, blocked, sources, rules, events] = await Promise.all([
+      api('GET', 'stats/packets'),
+      api('GET', 'stats/blocked'),
+      api('GET', 'stats/sources?limit=' + TOP),
+      api('GET', 'rules'),
+      api('GET', 'events?after=' + lastSeq + '&limit=' + MAX_EVENTS),
+    ]);
+    updateRates(packets);
+    drawBars('ports', Object.entries(blocked).sort((a, b) => b[1] - a[1]).slice(0, TOP));
+    drawBars('sources', sources.map((s) => [s.source, s.dropped]));
+    drawRules(rules);
+    addEvents(events);
+    setStatus(null);
+  } catch (e) {
+    setStatus(e);
+  }
+}
+
+refresh();
+setInterval(refresh, POLL_MS);
@@ -0,0 +1,111 @@
+use anyhow::bail;
+use aya::programs::{Xdp, XdpFlags};
+use clap::ValueEnum;
+use log::{info, warn};
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+
+use crate::pin::Pinning;
+
+/// How the XDP program is attached to an interface
+#[derive(ValueEnum, Enum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum XdpMode {
+    /// Driver mode, then generic mode when the driver has no native XDP
+    Auto,
+    /// Native XDP in the network driver
+    Driver,
+    /// Generic XDP in the kernel network stack, works with every driver but is slower
+    Generic,
+    /// Offloaded to the NIC, only some SmartNICs support it
+    Offload,
+}
+
+impl XdpMode {
+    fn flags(self) -> XdpFlags {
+        match self {
+            Self::Auto => XdpFlags::default(),
+            Self::Driver => XdpFlags::DRV_MODE,
+            Self::Generic => XdpFlags::SKB_MODE,
+            Self::Offload => XdpFlags::HW_MODE,
+        }
+    }
+
+    /// Modes tried in order
+    fn candidates(self) -> Vec<XdpMode> {
+        match self {
+            Self::Auto => vec![Self::Driver, Self::Generic],
+            mode => vec![mode],
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Driver => "driver",
+            Self::Generic => "generic",
+            Self::Offload => "offload",
+        }
+    }
+}
+
+/// Hook the ingress rules run at
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+#[oai(rename_all = "snake_case")]
+pub enum Datapath {
+    /// sdf_ingress, before the kernel allocates the packet
+    Xdp,
+    /// sdf_tc_ingress, for the interfaces listed in tc_ingress where XDP can't run
+    Tc,
+}
+
+/// How sdf filters the packets received on an interface
+#[derive(Object, Debug, Clone)]
+pub struct InterfaceStatus {
+    pub interface: String,
+    pub ifindex: u32,
+    pub datapath: Datapath,
+    /// Mode the XDP program runs in, missing with the TC datapath or when it replaced the
+    /// program of a pinned link, which keeps the mode it was first attached with
+    pub xdp_mode: Option<XdpMode>,
+    /// Program of xdp_chain sdf_ingress hands the packets it passes to
+    pub xdp_chain: Option<String>,
+}
+
+/// Attach the XDP program in the first mode of `mode` the interface supports
+pub fn attach_xdp(
+    pinning: &Pinning,
+    program: &mut Xdp,
+    iface: &str,
+    mode: XdpMode,
+) -> Result<Option<XdpMode>, anyhow::Error> {
+    let mut errors = vec![];
+    for candidate in mode.candidates() {
+        match pinning.attach_xdp(program, iface, candidate.flags()) {
+            Ok(true) => return Ok(None),
+            Ok(false) => {
+                info!(
+                    "attached sdf_ingress to {} in {} mode",
+                    iface,
+                    candidate.as_str()
+                );
+                return Ok(Some(candidate));
+            }
+            Err(e) => {
+                warn!(
+                    "attach sdf_ingress to {} in {} mode error {:#}",
+                    iface,
+                    candidate.as_str(),
+                    e
+                );
+                errors.push(format!("{} mode: {:#}", candidate.as_str(), e));
+            }
+        }
+    }
+    bail!(
+        "failed to attach sdf_ingress to {}, {}",
+        iface,
+        errors.join(", ")
+    )
+}
@@ -0,0 +1,199 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use log::warn;
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+
+use crate::http::ApiError;
+
+/// Number of records kept in memory for the audit query endpoint
+const RECENT_CAPACITY: usize = 1000;
+
+/// Who asked for a change
+#[derive(Debug, Clone)]
+pub struct Caller {
+    /// Name of the api token, `anonymous` when no token is configured
+    pub identity: String,
+    pub remote_addr: String,
+}
+
+impl Caller {
+    /// Changes made by sdf itself, like the initial config load
+    pub fn system() -> Self {
+        Self {
+            identity: "system".to_string(),
+            remote_addr: "local".to_string(),
+        }
+    }
+}
+
+/// Bearer tokens accepted by the control APIs
+#[derive(Debug, Clone, Default)]
+pub struct ApiTokens {
+    /// Map from token to token name, empty when the api is open
+    names: Arc<HashMap<String, String>>,
+}
+
+impl ApiTokens {
+    /// Build from the config map of token name to token
+    pub fn new(tokens: HashMap<String, String>) -> Self {
+        Self {
+            names: Arc::new(
+                tokens
+                    .into_iter()
+                    .map(|(name, token)| (token, name))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Resolve who is calling from the bearer token.
+    /// Returns None when tokens are configured and the request doesn't carry a valid one.
+    pub fn caller(&self, bearer: Option<&str>, remote_addr: String) -> Option<Caller> {
+        if self.names.is_empty() {
+            return Some(Caller {
+                identity: "anonymous".to_string(),
+                remote_addr,
+            });
+        }
+
+        let name = self.names.get(bearer?)?;
+        Some(Caller {
+            identity: name.clone(),
+            remote_addr,
+        })
+    }
+}
+
+#[derive(Object, Serialize, Deserialize, Debug, Clone)]
+pub struct AuditRecord {
+    /// RFC 3339 time of the change
+    pub timestamp: String,
+    pub caller: String,
+    pub remote_addr: String,
+    pub action: String,
+    pub rule: Option<String>,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+/// Append-only JSON lines log of every rule change
+pub struct AuditLog {
+    file: Option<File>,
+    recent: VecDeque<AuditRecord>,
+}
+
+impl AuditLog {
+    /// Open the audit file in append mode, or keep records only in memory when no path is given.
+    /// The tail of an existing file is loaded so the query endpoint survives restarts.
+    pub fn open(path: Option<&Path>) -> io::Result<Self> {
+        let mut recent = VecDeque::with_capacity(RECENT_CAPACITY);
+        let file = match path {
+            Some(path) => {
+                // a line cut by a crash must not swallow the next record
+                let mut truncated = false;
+                if let Ok(existing) = File::open(path) {
+                    let mut reader = BufReader::new(existing);
+                    let mut line = vec![];
+                    loop {
+                        line.clear();
+                        match reader.read_until(b'\n', &mut line) {
+                            Ok(0) => break,
+                            Ok(_) => {}
+                            Err(e) => {
+                                warn!("stop reading audit records of {:?}: {}", path, e);
+                                break;
+                            }
+                        }
+                        truncated = line.last() != Some(&b'\n');
+                        if line.iter().all(u8::is_ascii_whitespace) {
+                            continue;
+                        }
+                        match serde_json::from_slice::<AuditRecord>(&line) {
+                            Ok(record) => {
+                                if recent.len() == RECENT_CAPACITY {
+                                    recent.pop_front();
+                                }
+                                recent.push_back(record);
+                            }
+                            Err(e) => warn!("skip invalid audit record in {:?}: {}", path, e),
+                        }
+                    }
+                }
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                if truncated {
+                    file.write_all(b"\n")?;
+                }
+                Some(file)
+            }
+            None => None,
+        };
+        Ok(Self { file, recent })
+    }
+
+    pub fn record(
+        &mut self,
+        caller: &Caller,
+        action: &str,
+        rule: Option<String>,
+        error: Option<&ApiError>,
+    ) {
+        let record = AuditRecord {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            caller: caller.identity.clone(),
+            remote_addr: caller.remote_addr.clone(),
+            action: action.to_string(),
+            rule,
+            success: error.is_none(),
+            error: error.map(|e| e.to_string()),
+        };
+
+        if let Some(file) = &mut self.file {
+            let line = serde_json::to_string(&record).expect("Should serialize");
+            if let Err(e) = writeln!(file, "{}", line) {
+                warn!("write audit record error {}", e);
+            }
+        }
+
+        if self.recent.len() == RECENT_CAPACITY {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(record);
+    }
+
+    /// Newest records first
+    pub fn recent(&self, limit: usize) -> Vec<AuditRecord> {
+        self.recent.iter().rev().take(limit).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuditLog, Caller};
+
+    #[test]
+    fn open_skips_bad_lines() {
+        let path = std::env::temp_dir().join(format!("sdf-audit-test-{}", std::process::id()));
+        let mut log = AuditLog::open(Some(&path)).unwrap();
+        log.record(&Caller::system(), "reload", None, None);
+        drop(log);
+        // invalid utf-8, then a record cut by a crash
+        let mut content = std::fs::read(&path).unwrap();
+        content.extend(b"\xff\xfe\n{\"timestamp\":");
+        std::fs::write(&path, content).unwrap();
+
+        let mut log = AuditLog::open(Some(&path)).unwrap();
+        assert_eq!(log.recent(10).len(), 1);
+        log.record(&Caller::system(), "reload", None, None);
+        drop(log);
+        assert_eq!(AuditLog::open(Some(&path)).unwrap().recent(10).len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,339 @@
+use std::{
+    collections::{HashMap as StdHashMap, HashSet, VecDeque},
+    net::Ipv4Addr,
+    time::{Duration, Instant, SystemTime},
+};
+
+use aya::{
+    maps::{HashMap, MapError},
+    Bpf,
+};
+use log::{debug, warn};
+use poem_openapi::Object;
+use tokio::sync::broadcast::{self, error::TryRecvError};
+
+use crate::{
+    config::{AutoBanConfig, Detector},
+    events::DropEventInfo,
+    http::ApiError,
+    utils,
+};
+
+/// A source currently banned by a detector
+#[derive(Object, Debug, Clone)]
+pub struct AutoBanInfo {
+    pub source: String,
+    /// Name of the detector that fired
+    pub detector: String,
+    /// 1 for a first offense, the ban gets longer with every repeat
+    pub offense: u32,
+    /// RFC 3339 times of the ban and of its expiry
+    pub banned_at: String,
+    pub expires_at: String,
+}
+
+pub enum BanChange {
+    Banned(AutoBanInfo),
+    Expired(Ipv4Addr),
+}
+
+struct Ban {
+    info: AutoBanInfo,
+    expires: Instant,
+}
+
+struct Offense {
+    count: u32,
+    last: Instant,
+}
+
+/// fail2ban like automation, evaluated on every stats tick of the main loop
+pub struct AutoBan {
+    config: AutoBanConfig,
+    events: broadcast::Receiver<DropEventInfo>,
+    /// SOURCE_STATS at the previous tick, to compute per source drop rates
+    last_counts: StdHashMap<u32, u64>,
+    last_tick: Option<Instant>,
+    /// Destination ports of recent drop events per source
+    ports_seen: StdHashMap<u32, VecDeque<(Instant, u16)>>,
+    bans: StdHashMap<u32, Ban>,
+    offenses: StdHashMap<u32, Offense>,
+}
+
+impl AutoBan {
+    pub fn new(config: AutoBanConfig, events: broadcast::Receiver<DropEventInfo>) -> Self {
+        Self {
+            config,
+            events,
+            last_counts: StdHashMap::new(),
+            last_tick: None,
+            ports_seen: StdHashMap::new(),
+            bans: StdHashMap::new(),
+            offenses: StdHashMap::new(),
+        }
+    }
+
+    /// Expire old bans and ban the sources caught by a detector
+    pub fn tick(&mut self, bpf: &mut Bpf) -> Result<Vec<BanChange>, MapError> {
+        let now = Instant::now();
+        let offenders = self.detect(bpf, now)?;
+
+        let whitelist: HashMap<_, u32, u8> = HashMap::try_from(bpf.map("SRC_WHITELIST").unwrap())?;
+        let whitelisted: HashSet<u32> = whitelist.keys().flatten().collect();
+        let blacklist: HashMap<_, u32, u8> = HashMap::try_from(bpf.map("SRC_BLACKLIST").unwrap())?;
+        let blacklisted: HashSet<u32> = blacklist.keys().flatten().collect();
+        let mut auto_blacklist: HashMap<_, u32, u8> =
+            HashMap::try_from(bpf.map_mut("AUTO_BLACKLIST").unwrap())?;
+        let mut changes = vec![];
+
+        let expired: Vec<u32> = self
+            .bans
+            .iter()
+            .filter(|(_, ban)| ban.expires <= now)
+            .map(|(ip, _)| *ip)
+            .collect();
+        for ip in expired {
+            self.bans.remove(&ip);
+            // may already be evicted when the map was full
+            if let Err(e) = auto_blacklist.remove(&ip) {
+                debug!("remove expired ban {} error {}", Ipv4Addr::from(ip), e);
+            }
+            changes.push(BanChange::Expired(Ipv4Addr::from(ip)));
+        }
+        self.forget_offenses(now);
+
+        for (ip, detector) in offenders {
+            // manual rules are left alone
+            if self.bans.contains_key(&ip) || whitelisted.contains(&ip) || blacklisted.contains(&ip)
+            {
+                continue;
+            }
+
+            let count = self.offend(ip, now);
+            let ttl = self.ttl(count);
+
+            if let Err(e) = auto_blacklist.insert(ip, 0, 0) {
+                warn!("auto ban {} error {}", Ipv4Addr::from(ip), e);
+                continue;
+            }
+            let banned_at = SystemTime::now();
+            let info = AutoBanInfo {
+                source: Ipv4Addr::from(ip).to_string(),
+                detector,
+                offense: count,
+                banned_at: humantime::format_rfc3339_seconds(banned_at).to_string(),
+                expires_at: humantime::format_rfc3339_seconds(banned_at + ttl).to_string(),
+            };
+            changes.push(BanChange::Banned(info.clone()));
+            self.bans.insert(
+                ip,
+                Ban {
+                    info,
+                    expires: now + ttl,
+                },
+            );
+        }
+        Ok(changes)
+    }
+
+    /// Record an offense of the source, returns how many it has
+    fn offend(&mut self, ip: u32, now: Instant) -> u32 {
+        let offense = self.offenses.entry(ip).or_insert(Offense {
+            count: 0,
+            last: now,
+        });
+        offense.count += 1;
+        offense.last = now;
+        offense.count
+    }
+
+    /// Forget the offenses of the sources quiet for `forget_after`, their next one is a first
+    fn forget_offenses(&mut self, now: Instant) {
+        let forget_after = self.config.forget_after;
+        self.offenses
+            .retain(|_, offense| now.duration_since(offense.last) < forget_after);
+    }
+
+    /// Ban duration of the nth offense
+    fn ttl(&self, offense: u32) -> Duration {
+        let factor = 1u32 << offense.saturating_sub(1).min(16);
+        self.config
+            .ban_ttl
+            .saturating_mul(factor)
+            .min(self.config.max_ban_ttl)
+    }
+
+    /// Sources caught by a detector since the previous tick, with the detector name
+    fn detect(&mut self, bpf: &Bpf, now: Instant) -> Result<Vec<(u32, String)>, MapError> {
+        let mut offenders = vec![];
+
+        let sources: HashMap<_, u32, u64> = HashMap::try_from(bpf.map("SOURCE_STATS").unwrap())?;
+        let counts: StdHashMap<u32, u64> = sources.iter().flatten().collect();
+        if let Some(last_tick) = self.last_tick {
+            let elapsed = now.duration_since(last_tick).as_secs_f64();
+            for (ip, count) in &counts {
+                let before = self.last_counts.get(ip).copied().unwrap_or_default();
+                let rate = count.saturating_sub(before) as f64 / elapsed;
+                for detector in &self.config.detectors {
+                    if let Detector::DropRate { threshold } = detector.detector {
+                        if rate > threshold {
+                            offenders.push((*ip, detector.name.clone()));
+                        }
+                    }
+                }
+            }
+        }
+        self.last_counts = counts;
+        self.last_tick = Some(now);
+
+        self.read_events(now);
+        for detector in &self.config.detectors {
+            if let Detector::DistinctPorts { ports, window } = detector.detector {
+                for (ip, seen) in &self.ports_seen {
+                    let distinct: HashSet<u16> = seen
+                        .iter()
+                        .filter(|(time, _)| now.duration_since(*time) <= window)
+                        .map(|(_, port)| *port)
+                        .collect();
+                    if distinct.len() >= ports {
+                        offenders.push((*ip, detector.name.clone()));
+                    }
+                }
+            }
+        }
+        Ok(offenders)
+    }
+
+    /// Record the destination ports of the drop events received since the previous tick
+    fn read_events(&mut self, now: Instant) {
+        let window = self
+            .config
+            .detectors
+            .iter()
+            .filter_map(|detector| match detector.detector {
+                Detector::DistinctPorts { window, .. } => Some(window),
+                _ => None,
+            })
+            .max();
+
+        loop {
+            match self.events.try_recv() {
+                Ok(event) => {
+                    if window.is_none() {
+                        continue;
+                    }
+                    if let Some(ip) = utils::to_ipv4(&event.source) {
+                        let seen = self.ports_seen.entry(ip).or_default();
+                        seen.push_back((now, event.dest_port));
+                    }
+                }
+                Err(TryRecvError::Lagged(skipped)) => {
+                    warn!("auto ban lagged, skipped {} drop events", skipped);
+                }
+                Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => break,
+            }
+        }
+
+        let window = window.unwrap_or_default();
+        self.ports_seen.retain(|_, seen| {
+            while let Some((time, _)) = seen.front() {
+                if now.duration_since(*time) <= window {
+                    break;
+                }
+                seen.pop_front();
+            }
+            !seen.is_empty()
+        });
+    }
+
+    pub fn list(&self) -> Vec<AutoBanInfo> {
+        self.bans.values().map(|ban| ban.info.clone()).collect()
+    }
+
+    /// Remove an auto ban before it expires, repeat offenses are still counted
+    pub fn lift(&mut self, bpf: &mut Bpf, ip: &str) -> Result<String, ApiError> {
+        let addr = utils::to_ipv4(ip).ok_or(ApiError::OnlySupportIpV4)?;
+        self.bans.remove(&addr).ok_or(ApiError::IpNotFound)?;
+        let mut auto_blacklist: HashMap<_, u32, u8> =
+            HashMap::try_from(bpf.map_mut("AUTO_BLACKLIST").unwrap())
+                .map_err(|_| ApiError::CannotUpdateMap)?;
+        // an evicted ban is already lifted
+        let _ = auto_blacklist.remove(&addr);
+        Ok("REMOVED".to_string())
+    }
+
+    /// Lift the bans left in the pinned AUTO_BLACKLIST by a previous run, their expiry
+    /// went away with it
+    pub fn clear_pinned(&mut self, bpf: &mut Bpf) -> Result<usize, MapError> {
+        let mut auto_blacklist: HashMap<_, u32, u8> =
+            HashMap::try_from(bpf.map_mut("AUTO_BLACKLIST").unwrap())?;
+        let keys: Vec<u32> = auto_blacklist.keys().flatten().collect();
+        for ip in &keys {
+            let _ = auto_blacklist.remove(ip);
+        }
+        Ok(keys.len())
+    }
+
+    /// Drop the auto ban of a source that became a manual rule
+    pub fn forget(&mut self, bpf: &mut Bpf, ip: u32) {
+        if self.bans.remove(&ip).is_none() {
+            return;
+        }
+        match HashMap::<_, u32, u8>::try_from(bpf.map_mut("AUTO_BLACKLIST").unwrap()) {
+            Ok(mut auto_blacklist) => {
+                let _ = auto_blacklist.remove(&ip);
+            }
+            Err(e) => warn!("forget auto ban {} error {}", Ipv4Addr::from(ip), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use tokio::sync::broadcast;
+
+    use super::AutoBan;
+    use crate::config::AutoBanConfig;
+
+    fn auto_ban() -> AutoBan {
+        let config = AutoBanConfig {
+            ban_ttl: Duration::from_secs(60),
+            max_ban_ttl: Duration::from_secs(600),
+            forget_after: Duration::from_secs(3600),
+            detectors: vec![],
+        };
+        AutoBan::new(config, broadcast::channel(1).1)
+    }
+
+    #[test]
+    fn ttl_escalation() {
+        let auto_ban = auto_ban();
+        let ttls: Vec<_> = (1..=6)
+            .map(|offense| auto_ban.ttl(offense).as_secs())
+            .collect();
+        assert_eq!(ttls, [60, 120, 240, 480, 600, 600]);
+        // the shift is capped, a long offender doesn't overflow
+        assert_eq!(auto_ban.ttl(u32::MAX).as_secs(), 600);
+        assert_eq!(auto_ban.ttl(0).as_secs(), 60);
+    }
+
+    #[test]
+    fn offense_expiry() {
+        let mut auto_ban = auto_ban();
+        let start = Instant::now();
+        let minutes = |m| start + Duration::from_secs(m * 60);
+        assert_eq!(auto_ban.offend(1, minutes(0)), 1);
+        assert_eq!(auto_ban.offend(1, minutes(30)), 2);
+        assert_eq!(auto_ban.offend(2, minutes(30)), 1);
+
+        // the hour runs from the last offense
+        auto_ban.forget_offenses(minutes(80));
+        assert_eq!(auto_ban.offend(1, minutes(80)), 3);
+
+        auto_ban.forget_offenses(minutes(141));
+        assert!(auto_ban.offenses.is_empty());
+        assert_eq!(auto_ban.offend(1, minutes(141)), 1);
+    }
+}
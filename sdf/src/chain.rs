@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context};
+use aya::{
+    maps::{HashMap, ProgramArray},
+    programs::Xdp,
+    Bpf, BpfLoader,
+};
+use log::{info, warn};
+use sdf_common::XDP_CHAIN_ENTRIES;
+
+use crate::config::ChainProgram;
+
+/// Load the programs of `xdp_chain` and have sdf_ingress tail-call into them on the interfaces
+/// they are configured for. The objects are returned since their maps only live as long as
+/// they do
+pub fn load(
+    bpf: &mut Bpf,
+    interfaces: &[&(String, u32)],
+    chain: &BTreeMap<String, ChainProgram>,
+) -> Result<Vec<Bpf>, anyhow::Error> {
+    // everything is loaded before the maps change, a bad object leaves the chain of a
+    // previous run as it was
+    let mut objects = vec![];
+    let mut slots = vec![];
+    for (name, config) in chain {
+        let Some((_, ifindex)) = interfaces.iter().find(|(iface, _)| iface == name) else {
+            warn!(
+                "xdp_chain of {} is ignored, sdf_ingress isn't attached to it",
+                name
+            );
+            continue;
+        };
+        let mut object = BpfLoader::new()
+            .load_file(&config.object)
+            .with_context(|| format!("failed to load {}", config.object.display()))?;
+        let program: &mut Xdp = object
+            .program_mut(&config.program)
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} has no program {}",
+                    config.object.display(),
+                    config.program
+                )
+            })?
+            .try_into()
+            .with_context(|| format!("{} isn't an XDP program", config.program))?;
+        program.load()?;
+        info!(
+            "chained {} of {} after sdf_ingress on {}",
+            config.program,
+            config.object.display(),
+            name
+        );
+        slots.push((*ifindex, slots.len() as u32, config.program.as_str()));
+        objects.push(object);
+    }
+
+    {
+        let mut chained: HashMap<_, u32, u32> =
+            HashMap::try_from(bpf.map_mut("XDP_CHAIN_SLOTS").unwrap())?;
+        // interfaces chained by a previous run
+        let stale = chained.keys().collect::<Result<Vec<_>, _>>()?;
+        for ifindex in stale {
+            if !slots.iter().any(|(index, _, _)| *index == ifindex) {
+                chained.remove(&ifindex)?;
+            }
+        }
+    }
+    {
+        let mut programs = ProgramArray::try_from(bpf.map_mut("XDP_CHAIN").unwrap())?;
+        for ((_, slot, name), object) in slots.iter().zip(&objects) {
+            let program: &Xdp = object.program(name).unwrap().try_into()?;
+            programs.set(*slot, program.fd()?, 0)?;
+        }
+        for slot in slots.len() as u32..XDP_CHAIN_ENTRIES {
+            // most are already empty
+            let _ = programs.clear_index(&slot);
+        }
+    }
+    let mut chained: HashMap<_, u32, u32> =
+        HashMap::try_from(bpf.map_mut("XDP_CHAIN_SLOTS").unwrap())?;
+    for (ifindex, slot, _) in slots {
+        chained.insert(ifindex, slot, 0)?;
+    }
+    Ok(objects)
+}
@@ -0,0 +1,611 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt::Display,
+    hash::Hash,
+    net::Ipv4Addr,
+    path::PathBuf,
+};
+
+use clap::Args;
+use sdf_common::{PORT_MAP_ENTRIES, XDP_CHAIN_ENTRIES};
+use serde::Deserialize;
+
+use crate::{
+    config::{InterfaceRules, MapCapacities, Rule, StaticConfig},
+    geo, scan,
+};
+
+/// Exit codes of `sdf check`
+const EXIT_OK: i32 = 0;
+const EXIT_INVALID: i32 = 1;
+const EXIT_DIFFERS: i32 = 2;
+const EXIT_UNREACHABLE: i32 = 3;
+
+#[derive(Debug, Args)]
+pub struct CheckArgs {
+    /// Config file to validate, without loading the eBPF programs
+    config: PathBuf,
+
+    /// Control API of a running instance, like http://127.0.0.1:3000, to diff the rules against
+    #[clap(long)]
+    diff: Option<String>,
+
+    /// Bearer token for the running instance
+    #[clap(long, requires = "diff")]
+    token: Option<String>,
+
+    /// Treat warnings as errors
+    #[clap(long)]
+    strict: bool,
+
+    /// Exit with 2 when the rules differ from the running instance
+    #[clap(long, requires = "diff")]
+    exit_code: bool,
+}
+
+/// Problems found in a config, only errors make it invalid unless `--strict` is given
+#[derive(Debug, Default)]
+pub struct Report {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+pub async fn run(args: CheckArgs) -> i32 {
+    let config = match StaticConfig::load(&args.config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return EXIT_INVALID;
+        }
+    };
+
+    let report = check(&config);
+    for warning in &report.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    for error in &report.errors {
+        eprintln!("error: {}", error);
+    }
+    if !report.errors.is_empty() || (args.strict && !report.warnings.is_empty()) {
+        return EXIT_INVALID;
+    }
+    println!("{}: ok", args.config.display());
+
+    let Some(endpoint) = args.diff else {
+        return EXIT_OK;
+    };
+    let running = match fetch_rules(&endpoint, args.token.as_deref()).await {
+        Ok(rules) => rules,
+        Err(e) => {
+            eprintln!("error: fetch rules of {} error {}", endpoint, e);
+            return EXIT_UNREACHABLE;
+        }
+    };
+    let changes = diff(&config, &running);
+    for change in &changes {
+        println!("{}", change);
+    }
+    if args.exit_code && !changes.is_empty() {
+        EXIT_DIFFERS
+    } else {
+        EXIT_OK
+    }
+}
+
+pub fn check(config: &StaticConfig) -> Report {
+    let mut report = Report::default();
+
+    rule_lists(
+        &mut report,
+        "",
+        &config.source_blacklist,
+        &config.source_whitelist,
+        &config.port_blacklist,
+    );
+    for (name, rules) in &config.interfaces {
+        if !is_interface_name(name) {
+            report
+                .errors
+                .push(format!("{:?} is not an interface name", name));
+        }
+        rule_lists(
+            &mut report,
+            &format!("interfaces.{}.", name),
+            &rules.source_blacklist,
+            &rules.source_whitelist,
+            &rules.port_blacklist,
+        );
+    }
+    for name in &config.tc_ingress {
+        if !is_interface_name(name) {
+            report
+                .errors
+                .push(format!("tc_ingress {:?} is not an interface name", name));
+        }
+    }
+    for (name, chain) in &config.xdp_chain {
+        if !is_interface_name(name) {
+            report
+                .errors
+                .push(format!("{:?} is not an interface name", name));
+        }
+        if config.tc_ingress.contains(name) {
+            report.errors.push(format!(
+                "xdp_chain.{} can't run on a tc_ingress interface",
+                name
+            ));
+        }
+        if chain.program.is_empty() {
+            report
+                .errors
+                .push(format!("xdp_chain.{} has no program name", name));
+        }
+        // the config may be checked away from the host it's deployed to
+        if !chain.object.is_file() {
+            report.warnings.push(format!(
+                "xdp_chain.{} object {} doesn't exist",
+                name,
+                chain.object.display()
+            ));
+        }
+    }
+    capacity(
+        &mut report,
+        "xdp_chain",
+        config.xdp_chain.len(),
+        XDP_CHAIN_ENTRIES,
+    );
+    let capacities = config.map_capacities.unwrap_or_default();
+    if let Err(e) = capacities.validate() {
+        report.errors.push(e);
+    }
+    report.errors.extend(capacity_errors(config, &capacities));
+
+    for name in &config.source_whitelist_dns {
+        if name.is_empty() || name.contains(char::is_whitespace) || name.parse::<Ipv4Addr>().is_ok()
+        {
+            report.errors.push(format!(
+                "source_whitelist_dns {:?} is not a host name",
+                name
+            ));
+        }
+    }
+
+    let mut feeds = HashSet::new();
+    for feed in &config.feeds {
+        if !feeds.insert(&feed.name) {
+            report
+                .errors
+                .push(format!("feed {} is defined twice", feed.name));
+        }
+    }
+    if let Some(auto_ban) = &config.auto_ban {
+        let mut detectors = HashSet::new();
+        for detector in &auto_ban.detectors {
+            if !detectors.insert(&detector.name) {
+                report.errors.push(format!(
+                    "auto_ban detector {} is defined twice",
+                    detector.name
+                ));
+            }
+        }
+    }
+    if let Some(scan) = &config.scan_detection {
+        if let Err(e) = scan::validate(scan) {
+            report.errors.push(e);
+        }
+    }
+    if let Some(trap) = &config.trap_ports {
+        capacity(
+            &mut report,
+            "trap_ports",
+            trap.ports.len(),
+            PORT_MAP_ENTRIES,
+        );
+        let blacklisted: HashSet<u16> = config.port_blacklist.iter().map(|r| r.value).collect();
+        for port in &trap.ports {
+            if blacklisted.contains(port) {
+                report.warnings.push(format!(
+                    "trap port {} is also blacklisted, udp packets to it are dropped without banning",
+                    port
+                ));
+            }
+        }
+    }
+    if let Some(geo) = &config.geo {
+        if let Err(e) = geo::validate(geo) {
+            report.errors.push(e);
+        }
+        // like the xdp_chain objects, the databases may only exist on the target host
+        for path in geo::missing_databases(geo) {
+            report
+                .warnings
+                .push(format!("geo database {} doesn't exist", path.display()));
+        }
+        let ports: HashSet<u16> = geo.rules.iter().flat_map(|r| r.ports.clone()).collect();
+        capacity(&mut report, "geo ports", ports.len(), PORT_MAP_ENTRIES);
+    }
+    report
+}
+
+/// Checks shared by the global rule lists and the ones of each interface
+fn rule_lists(
+    report: &mut Report,
+    prefix: &str,
+    source_blacklist: &[Rule<Ipv4Addr>],
+    source_whitelist: &[Rule<Ipv4Addr>],
+    port_blacklist: &[Rule<u16>],
+) {
+    for (name, rules) in [
+        ("source_blacklist", source_blacklist),
+        ("source_whitelist", source_whitelist),
+    ] {
+        let name = format!("{}{}", prefix, name);
+        for rule in rules {
+            let ip = rule.value;
+            if ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() {
+                report
+                    .errors
+                    .push(format!("{} {} can't be a packet source", name, rule));
+            } else if ip.is_loopback() {
+                report
+                    .warnings
+                    .push(format!("{} {} is a loopback address", name, rule));
+            }
+        }
+        duplicates(report, &name, rules);
+    }
+    let name = format!("{}port_blacklist", prefix);
+    for rule in port_blacklist {
+        if rule.value == 0 {
+            report
+                .errors
+                .push(format!("{} {} is not a valid port", name, rule));
+        }
+    }
+    duplicates(report, &name, port_blacklist);
+
+    let whitelist: HashSet<Ipv4Addr> = source_whitelist.iter().map(|r| r.value).collect();
+    for rule in source_blacklist {
+        if whitelist.contains(&rule.value) {
+            report.errors.push(format!(
+                "{}{} is both whitelisted and blacklisted, the whitelist wins",
+                prefix, rule
+            ));
+        }
+    }
+}
+
+fn is_interface_name(name: &str) -> bool {
+    // IFNAMSIZ with the trailing nul
+    !name.is_empty() && name.len() <= 15 && !name.contains(['/', ' '])
+}
+
+fn duplicates<T: Hash + Eq + Display>(report: &mut Report, name: &str, rules: &[Rule<T>]) {
+    let mut seen = HashSet::new();
+    for rule in rules {
+        if !seen.insert(&rule.value) {
+            report
+                .warnings
+                .push(format!("{} {} is listed more than once", name, rule));
+        }
+    }
+}
+
+/// Rule lists that don't fit in their map, also checked at startup and on reload
+pub fn capacity_errors(config: &StaticConfig, capacities: &MapCapacities) -> Vec<String> {
+    let mut report = Report::default();
+    for (name, len, max_entries) in [
+        (
+            "source_blacklist",
+            config.source_blacklist.len(),
+            capacities.source_blacklist,
+        ),
+        (
+            "source_whitelist",
+            config.source_whitelist.len(),
+            capacities.source_whitelist,
+        ),
+        (
+            "port_blacklist",
+            config.port_blacklist.len(),
+            capacities.port_blacklist,
+        ),
+    ] {
+        capacity(&mut report, name, len, max_entries);
+    }
+    // one map per kind of rule holds the rules of every interface
+    let interfaces = config.interfaces.values();
+    for (name, len) in [
+        (
+            "interface source_blacklist",
+            interfaces.clone().map(|r| r.source_blacklist.len()).sum(),
+        ),
+        (
+            "interface source_whitelist",
+            interfaces.clone().map(|r| r.source_whitelist.len()).sum(),
+        ),
+        (
+            "interface port_blacklist",
+            interfaces.map(|r| r.port_blacklist.len()).sum(),
+        ),
+    ] {
+        capacity(&mut report, name, len, capacities.interface_rules);
+    }
+    report.errors
+}
+
+fn capacity(report: &mut Report, name: &str, len: usize, max_entries: u32) {
+    if len > max_entries as usize {
+        report.errors.push(format!(
+            "{} has {} entries, the map only holds {}",
+            name, len, max_entries
+        ));
+    }
+}
+
+/// `data` of `GET /v1/rules`
+#[derive(Deserialize)]
+struct RunningRules {
+    source_blacklist: Vec<String>,
+    source_whitelist: Vec<String>,
+    port_blacklist: Vec<u16>,
+    /// Resolved dns names, a reload keeps them. Missing before the field was added
+    #[serde(default)]
+    dns_whitelist: Vec<String>,
+    /// Missing before the per interface rules were listed
+    #[serde(default)]
+    interfaces: Vec<RunningInterfaceRules>,
+}
+
+#[derive(Deserialize, Default)]
+struct RunningInterfaceRules {
+    interface: String,
+    source_blacklist: Vec<String>,
+    source_whitelist: Vec<String>,
+    port_blacklist: Vec<u16>,
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    status: bool,
+    error: Option<String>,
+    data: Option<RunningRules>,
+}
+
+async fn fetch_rules(endpoint: &str, token: Option<&str>) -> Result<RunningRules, String> {
+    let mut req =
+        reqwest::Client::new().get(format!("{}/v1/rules", endpoint.trim_end_matches('/')));
+    if let Some(token) = token {
+        req = req.bearer_auth(token);
+    }
+    let body = req
+        .send()
+        .await
+        .map_err(|e| e.to_string())?
+        .text()
+        .await
+        .map_err(|e| e.to_string())?;
+    let envelope: Envelope =
+        serde_json::from_str(&body).map_err(|e| format!("invalid response {}", e))?;
+    match (envelope.status, envelope.data) {
+        (true, Some(rules)) => Ok(rules),
+        _ => Err(envelope.error.unwrap_or_default()),
+    }
+}
+
+/// `+` for rules a reload would add, `-` for the ones it would remove. Rules of an
+/// interface the instance isn't attached to always show as added
+fn diff(config: &StaticConfig, running: &RunningRules) -> Vec<String> {
+    let mut changes = vec![];
+    let mut compare = |name: &str, wanted: BTreeSet<String>, current: BTreeSet<String>| {
+        for value in wanted.difference(&current) {
+            changes.push(format!("+ {} {}", name, value));
+        }
+        for value in current.difference(&wanted) {
+            changes.push(format!("- {} {}", name, value));
+        }
+    };
+    compare(
+        "source_blacklist",
+        config
+            .source_blacklist
+            .iter()
+            .map(|r| r.value.to_string())
+            .collect(),
+        running.source_blacklist.iter().cloned().collect(),
+    );
+    compare(
+        "source_whitelist",
+        config
+            .source_whitelist
+            .iter()
+            .map(|r| r.value.to_string())
+            .collect(),
+        running
+            .source_whitelist
+            .iter()
+            .filter(|ip| !running.dns_whitelist.contains(ip))
+            .cloned()
+            .collect(),
+    );
+    compare(
+        "port_blacklist",
+        config
+            .port_blacklist
+            .iter()
+            .map(|r| r.value.to_string())
+            .collect(),
+        running
+            .port_blacklist
+            .iter()
+            .map(|port| port.to_string())
+            .collect(),
+    );
+
+    let (no_config, no_rules) = (InterfaceRules::default(), RunningInterfaceRules::default());
+    let names: BTreeSet<&String> = config
+        .interfaces
+        .keys()
+        .chain(running.interfaces.iter().map(|rules| &rules.interface))
+        .collect();
+    for name in names {
+        let wanted = config.interfaces.get(name).unwrap_or(&no_config);
+        let current = running
+            .interfaces
+            .iter()
+            .find(|rules| &rules.interface == name)
+            .unwrap_or(&no_rules);
+        let ips = |rules: &[Rule<Ipv4Addr>]| -> BTreeSet<String> {
+            rules.iter().map(|r| r.value.to_string()).collect()
+        };
+        compare(
+            &format!("interfaces.{}.source_blacklist", name),
+            ips(&wanted.source_blacklist),
+            current.source_blacklist.iter().cloned().collect(),
+        );
+        compare(
+            &format!("interfaces.{}.source_whitelist", name),
+            ips(&wanted.source_whitelist),
+            current.source_whitelist.iter().cloned().collect(),
+        );
+        compare(
+            &format!("interfaces.{}.port_blacklist", name),
+            wanted
+                .port_blacklist
+                .iter()
+                .map(|r| r.value.to_string())
+                .collect(),
+            current
+                .port_blacklist
+                .iter()
+                .map(|port| port.to_string())
+                .collect(),
+        );
+    }
+    changes
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use super::{check, diff, RunningInterfaceRules, RunningRules};
+    use crate::config::{ChainProgram, InterfaceRules, Rule, StaticConfig};
+
+    fn rule<T>(value: T) -> Rule<T> {
+        Rule {
+            value,
+            description: None,
+            set: None,
+        }
+    }
+
+    #[test]
+    fn find_problems() {
+        let config = StaticConfig {
+            source_blacklist: vec![
+                rule(Ipv4Addr::new(10, 0, 0, 1)),
+                rule(Ipv4Addr::new(0, 0, 0, 0)),
+            ],
+            source_whitelist: vec![rule(Ipv4Addr::new(10, 0, 0, 1))],
+            port_blacklist: vec![rule(53), rule(53)],
+            ..Default::default()
+        };
+        let report = check(&config);
+        assert_eq!(report.errors.len(), 2, "{:?}", report.errors);
+        assert_eq!(report.warnings.len(), 1, "{:?}", report.warnings);
+
+        let config = StaticConfig {
+            port_blacklist: (1..=5000).map(rule).collect(),
+            ..Default::default()
+        };
+        let report = check(&config);
+        assert!(report.errors[0].contains("only holds 4096"));
+
+        let config = StaticConfig {
+            interfaces: [(
+                "eth1".to_string(),
+                InterfaceRules {
+                    port_blacklist: vec![rule(0)],
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let report = check(&config);
+        assert_eq!(
+            report.errors,
+            ["interfaces.eth1.port_blacklist 0 is not a valid port"]
+        );
+
+        let config = StaticConfig {
+            tc_ingress: vec!["wg0".to_string()],
+            xdp_chain: [(
+                "wg0".to_string(),
+                ChainProgram {
+                    object: "/nonexistent/lb.o".into(),
+                    program: "xdp_lb".to_string(),
+                },
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let report = check(&config);
+        assert_eq!(
+            report.errors,
+            ["xdp_chain.wg0 can't run on a tc_ingress interface"]
+        );
+        assert_eq!(
+            report.warnings,
+            ["xdp_chain.wg0 object /nonexistent/lb.o doesn't exist"]
+        );
+    }
+
+    #[test]
+    fn diff_running() {
+        let config = StaticConfig {
+            source_whitelist: vec![rule(Ipv4Addr::new(10, 0, 0, 1))],
+            port_blacklist: vec![rule(53), rule(123)],
+            interfaces: [(
+                "eth1".to_string(),
+                InterfaceRules {
+                    source_blacklist: vec![rule(Ipv4Addr::new(10, 0, 0, 2))],
+                    port_blacklist: vec![rule(22)],
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let running = RunningRules {
+            source_blacklist: vec!["10.0.0.9".to_string()],
+            source_whitelist: vec!["10.0.0.1".to_string(), "1.1.1.1".to_string()],
+            port_blacklist: vec![53],
+            dns_whitelist: vec!["1.1.1.1".to_string()],
+            interfaces: vec![
+                RunningInterfaceRules {
+                    interface: "eth1".to_string(),
+                    port_blacklist: vec![22, 80],
+                    ..Default::default()
+                },
+                RunningInterfaceRules {
+                    interface: "eth2".to_string(),
+                    source_whitelist: vec!["10.0.0.3".to_string()],
+                    ..Default::default()
+                },
+            ],
+        };
+        assert_eq!(
+            diff(&config, &running),
+            [
+                "- source_blacklist 10.0.0.9",
+                "+ port_blacklist 123",
+                "+ interfaces.eth1.source_blacklist 10.0.0.2",
+                "- interfaces.eth1.port_blacklist 80",
+                "- interfaces.eth2.source_whitelist 10.0.0.3",
+            ]
+        );
+    }
+}
@@ -1,9 +1,374 @@
+use sdf_common::RULE_MAP_ENTRIES;
 use serde::{Deserialize, Serialize};
-use std::net::Ipv4Addr;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::Ipv4Addr,
+    path::PathBuf,
+    time::Duration,
+};
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::attach::XdpMode;
+
+mod loader;
+
+pub use loader::ConfigError;
+
+/// Config file with its includes resolved, sets expanded and every file merged
+#[derive(Debug, Clone, Default)]
 pub struct StaticConfig {
-    pub source_blacklist: Vec<Ipv4Addr>,
-    pub source_whitelist: Vec<Ipv4Addr>,
-    pub port_blacklist: Vec<u16>,
+    pub source_blacklist: Vec<Rule<Ipv4Addr>>,
+    pub source_whitelist: Vec<Rule<Ipv4Addr>>,
+    /// Host names whose IPv4 addresses are whitelisted, resolved again when their TTL expires
+    pub source_whitelist_dns: Vec<String>,
+    pub port_blacklist: Vec<Rule<u16>>,
+    /// Rules only applied to the packets received on one interface, on top of the global ones
+    pub interfaces: BTreeMap<String, InterfaceRules>,
+    /// TLS settings for the control API, only read at startup
+    pub tls: Option<TlsConfig>,
+    /// Map from token name to bearer token, when not empty every api call needs one of them.
+    /// The name is written to the audit log, only read at startup
+    pub api_tokens: HashMap<String, String>,
+    /// Detectors banning sources automatically, only read at startup
+    pub auto_ban: Option<AutoBanConfig>,
+    /// Datapath scan detector, disabled when missing
+    pub scan_detection: Option<ScanDetectionConfig>,
+    /// Ports no legit client ever sends to, their sources get banned by the datapath
+    pub trap_ports: Option<TrapConfig>,
+    /// External blocklists refreshed by the daemon, only read at startup
+    pub feeds: Vec<FeedConfig>,
+    /// Country and ASN rules, only read at startup
+    pub geo: Option<GeoConfig>,
+    /// Sizes of the rule maps, only read at startup since the maps are created with the programs
+    pub map_capacities: Option<MapCapacities>,
+    /// How the XDP program is attached, only read at startup
+    pub xdp_mode: Option<XdpMode>,
+    /// Interfaces filtered by the TC ingress classifier instead of XDP, only read at startup
+    pub tc_ingress: Vec<String>,
+    /// XDP program of another object run after sdf passes a packet, by interface name, only
+    /// read at startup
+    pub xdp_chain: BTreeMap<String, ChainProgram>,
+}
+
+impl StaticConfig {
+    /// Read a YAML, TOML or JSON config file, picked from the extension, and the files it includes
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, ConfigError> {
+        loader::load(path.as_ref())
+    }
+}
+
+/// Rule lists of one interface, by interface name
+#[derive(Debug, Clone, Default)]
+pub struct InterfaceRules {
+    pub source_blacklist: Vec<Rule<Ipv4Addr>>,
+    pub source_whitelist: Vec<Rule<Ipv4Addr>>,
+    pub port_blacklist: Vec<Rule<u16>>,
+}
+
+/// One address or port of a rule list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule<T> {
+    pub value: T,
+    pub description: Option<String>,
+    /// Named set the value comes from
+    pub set: Option<String>,
+}
+
+impl<T: std::fmt::Display> std::fmt::Display for Rule<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.value)?;
+        match (&self.set, &self.description) {
+            (Some(set), Some(description)) => write!(f, " (@{}: {})", set, description),
+            (Some(set), None) => write!(f, " (@{})", set),
+            (None, Some(description)) => write!(f, " ({})", description),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// Max entries of the rule maps and of the maps holding automatic entries
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct MapCapacities {
+    #[serde(default = "default_rule_map_entries")]
+    pub source_blacklist: u32,
+    #[serde(default = "default_rule_map_entries")]
+    pub source_whitelist: u32,
+    #[serde(default = "default_rule_map_entries")]
+    pub port_blacklist: u32,
+    /// LRU map of the destinations whitelisted by the egress program
+    #[serde(default = "default_rule_map_entries")]
+    pub auto_whitelist: u32,
+    /// LRU map of the auto bans
+    #[serde(default = "default_rule_map_entries")]
+    pub auto_blacklist: u32,
+    /// Each of the per interface rule maps, shared by all the interfaces
+    #[serde(default = "default_rule_map_entries")]
+    pub interface_rules: u32,
+    /// Occupancy, in percent of the capacity, at which a map is reported as filling up
+    #[serde(default = "default_warn_percent")]
+    pub warn_percent: u8,
+}
+
+impl Default for MapCapacities {
+    fn default() -> Self {
+        Self {
+            source_blacklist: RULE_MAP_ENTRIES,
+            source_whitelist: RULE_MAP_ENTRIES,
+            port_blacklist: RULE_MAP_ENTRIES,
+            auto_whitelist: RULE_MAP_ENTRIES,
+            auto_blacklist: RULE_MAP_ENTRIES,
+            interface_rules: RULE_MAP_ENTRIES,
+            warn_percent: default_warn_percent(),
+        }
+    }
+}
+
+fn default_rule_map_entries() -> u32 {
+    RULE_MAP_ENTRIES
+}
+
+fn default_warn_percent() -> u8 {
+    80
+}
+
+impl MapCapacities {
+    /// Map names with their capacity, in the order they are reported
+    pub fn maps(&self) -> [(&'static str, u32); 8] {
+        [
+            ("SRC_BLACKLIST", self.source_blacklist),
+            ("SRC_WHITELIST", self.source_whitelist),
+            ("PORT_BLACKLIST", self.port_blacklist),
+            ("AUTO_WHITELIST", self.auto_whitelist),
+            ("AUTO_BLACKLIST", self.auto_blacklist),
+            ("IFACE_SRC_BLACKLIST", self.interface_rules),
+            ("IFACE_SRC_WHITELIST", self.interface_rules),
+            ("IFACE_PORT_BLACKLIST", self.interface_rules),
+        ]
+    }
+
+    /// Capacity of a map, by its map name
+    pub fn of(&self, map: &str) -> u32 {
+        self.maps()
+            .into_iter()
+            .find(|(name, _)| *name == map)
+            .map_or(0, |(_, entries)| entries)
+    }
+
+    /// Override one capacity, like `source_blacklist=65536` from the command line
+    pub fn set(&mut self, name: &str, entries: u32) -> Result<(), String> {
+        match name {
+            "source_blacklist" => self.source_blacklist = entries,
+            "source_whitelist" => self.source_whitelist = entries,
+            "port_blacklist" => self.port_blacklist = entries,
+            "auto_whitelist" => self.auto_whitelist = entries,
+            "auto_blacklist" => self.auto_blacklist = entries,
+            "interface_rules" => self.interface_rules = entries,
+            _ => return Err(format!("unknown map {}", name)),
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (map, entries) in self.maps() {
+            if entries == 0 {
+                return Err(format!("{} capacity can't be 0", map));
+            }
+        }
+        if self.warn_percent == 0 || self.warn_percent > 100 {
+            return Err("warn_percent must be between 1 and 100".to_string());
+        }
+        if self.port_blacklist > u16::MAX as u32 + 1 {
+            return Err(format!(
+                "PORT_BLACKLIST capacity can't be above {}, the number of ports",
+                u16::MAX as u32 + 1
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain
+    pub cert: PathBuf,
+    /// PEM encoded private key
+    pub key: PathBuf,
+    /// PEM encoded CA bundle, when set every client must present a certificate signed by it
+    pub client_ca: Option<PathBuf>,
+}
+
+/// XDP program sdf_ingress tail-calls into, its verdict replaces the XDP_PASS of sdf
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ChainProgram {
+    /// Compiled eBPF object file
+    pub object: PathBuf,
+    /// Name of the XDP program in the object
+    pub program: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AutoBanConfig {
+    /// How long a first offense is banned, doubled for every repeat offense
+    #[serde(with = "duration", default = "default_ban_ttl")]
+    pub ban_ttl: Duration,
+    /// Longest ban given to repeat offenders
+    #[serde(with = "duration", default = "default_max_ban_ttl")]
+    pub max_ban_ttl: Duration,
+    /// Offenses older than this don't count as repeats anymore
+    #[serde(with = "duration", default = "default_forget_after")]
+    pub forget_after: Duration,
+    #[serde(default)]
+    pub detectors: Vec<DetectorConfig>,
+}
+
+impl Default for AutoBanConfig {
+    fn default() -> Self {
+        Self {
+            ban_ttl: default_ban_ttl(),
+            max_ban_ttl: default_max_ban_ttl(),
+            forget_after: default_forget_after(),
+            detectors: vec![],
+        }
+    }
+}
+
+fn default_ban_ttl() -> Duration {
+    Duration::from_secs(10 * 60)
+}
+
+fn default_max_ban_ttl() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+fn default_forget_after() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DetectorConfig {
+    /// Written to the audit log and the ban list
+    pub name: String,
+    #[serde(flatten)]
+    pub detector: Detector,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Detector {
+    /// More than `threshold` dropped packets per second from one source,
+    /// like a reflection flood from a blacklisted port
+    DropRate { threshold: f64 },
+    /// Dropped packets from one source to at least `ports` distinct destination ports within `window`
+    DistinctPorts {
+        ports: usize,
+        #[serde(with = "duration")]
+        window: Duration,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScanDetectionConfig {
+    /// Distinct destinations are counted over the last one to two windows
+    #[serde(with = "duration")]
+    pub window: Duration,
+    /// Distinct destination ports flagging a source, 0 to not count ports
+    #[serde(default)]
+    pub max_ports: u32,
+    /// Distinct destination hosts flagging a source, 0 to not count hosts
+    #[serde(default)]
+    pub max_hosts: u32,
+    #[serde(default)]
+    pub action: ScanActionConfig,
+    /// How long a detected source is dropped with the `drop` action
+    #[serde(with = "duration", default = "default_scan_block")]
+    pub block: Duration,
+}
+
+fn default_scan_block() -> Duration {
+    Duration::from_secs(10 * 60)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanActionConfig {
+    /// Only report detections as events and counters
+    #[default]
+    Report,
+    /// Also drop the source for `block`
+    Drop,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrapConfig {
+    pub ports: Vec<u16>,
+    /// How long a source hitting one of the ports stays banned
+    #[serde(with = "duration", default = "default_trap_ban_ttl")]
+    pub ban_ttl: Duration,
+}
+
+fn default_trap_ban_ttl() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeedConfig {
+    /// Shown in the feed status, must be unique
+    pub name: String,
+    /// Local file path or http(s) URL
+    pub source: String,
+    #[serde(with = "duration", default = "default_feed_refresh")]
+    pub refresh: Duration,
+}
+
+fn default_feed_refresh() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GeoConfig {
+    /// MaxMind format country database, like GeoLite2-Country.mmdb
+    pub country_db: Option<PathBuf>,
+    /// MaxMind format ASN database, like GeoLite2-ASN.mmdb
+    pub asn_db: Option<PathBuf>,
+    #[serde(default)]
+    pub rules: Vec<GeoRuleConfig>,
+}
+
+/// Drop sources of a country or an AS, set exactly one of `country` and `asn`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GeoRuleConfig {
+    /// ISO 3166 code, like `CN`
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    /// Only drop traffic to these service ports, empty for every port
+    #[serde(default)]
+    pub ports: Vec<u16>,
+}
+
+impl GeoRuleConfig {
+    /// Name shown in drop events, like `country:CN` or `asn:4134`
+    pub fn name(&self) -> String {
+        match (&self.country, self.asn) {
+            (Some(country), _) => format!("country:{}", country.to_uppercase()),
+            (None, Some(asn)) => format!("asn:{}", asn),
+            (None, None) => "invalid".to_string(),
+        }
+    }
+}
+
+/// Durations written like `10m` or `1h 30m`
+mod duration {
+    use std::time::Duration;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&humantime::format_duration(*value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        humantime::parse_duration(&value).map_err(D::Error::custom)
+    }
 }
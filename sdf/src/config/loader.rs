@@ -0,0 +1,539 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Deserialize};
+
+use super::{
+    AutoBanConfig, ChainProgram, FeedConfig, GeoConfig, MapCapacities, Rule, ScanDetectionConfig,
+    StaticConfig, TlsConfig, TrapConfig, XdpMode,
+};
+
+/// Extensions of the files loaded from an included directory
+const EXTENSIONS: [&str; 4] = ["yaml", "yml", "toml", "json"];
+
+/// Invalid config, the message says where in the file when the parser knows it
+#[derive(Debug)]
+pub struct ConfigError {
+    pub file: PathBuf,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(file: &Path, message: impl Into<String>) -> Self {
+        Self {
+            file: file.to_path_buf(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.file.display(), self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Content of a single file, before includes are merged and sets expanded
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    /// Files or directories, relative to this file
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    #[serde(default)]
+    sets: HashMap<String, SetConfig>,
+    #[serde(default)]
+    source_blacklist: Vec<RuleEntry<Ipv4Addr>>,
+    #[serde(default)]
+    source_whitelist: Vec<RuleEntry<Ipv4Addr>>,
+    #[serde(default)]
+    source_whitelist_dns: Vec<String>,
+    #[serde(default)]
+    port_blacklist: Vec<RuleEntry<u16>>,
+    #[serde(default)]
+    interfaces: BTreeMap<String, InterfaceFile>,
+    tls: Option<TlsConfig>,
+    #[serde(default)]
+    api_tokens: HashMap<String, String>,
+    auto_ban: Option<AutoBanConfig>,
+    scan_detection: Option<ScanDetectionConfig>,
+    trap_ports: Option<TrapConfig>,
+    #[serde(default)]
+    feeds: Vec<FeedConfig>,
+    geo: Option<GeoConfig>,
+    map_capacities: Option<MapCapacities>,
+    xdp_mode: Option<XdpMode>,
+    #[serde(default)]
+    tc_ingress: Vec<String>,
+    #[serde(default)]
+    xdp_chain: BTreeMap<String, ChainProgram>,
+}
+
+/// Rules of one interface, merged with the ones other files give the same interface
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InterfaceFile {
+    #[serde(default)]
+    source_blacklist: Vec<RuleEntry<Ipv4Addr>>,
+    #[serde(default)]
+    source_whitelist: Vec<RuleEntry<Ipv4Addr>>,
+    #[serde(default)]
+    port_blacklist: Vec<RuleEntry<u16>>,
+}
+
+/// Named group of addresses and ports that rules refer to with `set: name`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetConfig {
+    description: Option<String>,
+    #[serde(default)]
+    addresses: Vec<Ipv4Addr>,
+    #[serde(default)]
+    ports: Vec<u16>,
+}
+
+/// `1.2.3.4`, `{value: 1.2.3.4, description: ...}` or `{set: name, description: ...}`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RuleEntry<T> {
+    Value(T),
+    Rule {
+        value: T,
+        description: Option<String>,
+    },
+    Set {
+        set: String,
+        description: Option<String>,
+    },
+}
+
+pub fn load(path: &Path) -> Result<StaticConfig, ConfigError> {
+    let mut files = vec![];
+    read_tree(path, &mut vec![], &mut HashSet::new(), &mut files)?;
+    merge(files)
+}
+
+/// Read a file then the ones it includes, depth first. A file included twice is only read once
+fn read_tree(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    seen: &mut HashSet<PathBuf>,
+    files: &mut Vec<(PathBuf, ConfigFile)>,
+) -> Result<(), ConfigError> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| ConfigError::new(path, e.to_string()))?;
+    if stack.contains(&canonical) {
+        return Err(ConfigError::new(path, "include cycle"));
+    }
+    if !seen.insert(canonical.clone()) {
+        return Ok(());
+    }
+
+    let mut file = read_file(path)?;
+    let includes = std::mem::take(&mut file.include);
+    files.push((path.to_path_buf(), file));
+
+    stack.push(canonical);
+    let dir = path.parent().unwrap_or(Path::new("."));
+    for include in includes {
+        let include = dir.join(include);
+        if include.is_dir() {
+            for entry in dir_entries(&include)? {
+                read_tree(&entry, stack, seen, files)?;
+            }
+        } else {
+            read_tree(&include, stack, seen, files)?;
+        }
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// Config files of a directory in name order
+fn dir_entries(dir: &Path) -> Result<Vec<PathBuf>, ConfigError> {
+    let mut entries = vec![];
+    for entry in std::fs::read_dir(dir).map_err(|e| ConfigError::new(dir, e.to_string()))? {
+        let path = entry
+            .map_err(|e| ConfigError::new(dir, e.to_string()))?
+            .path();
+        let supported = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| EXTENSIONS.contains(&ext));
+        if path.is_file() && supported {
+            entries.push(path);
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+fn read_file(path: &Path) -> Result<ConfigFile, ConfigError> {
+    let text = std::fs::read_to_string(path).map_err(|e| ConfigError::new(path, e.to_string()))?;
+    let text = substitute_env(&text, |name| std::env::var(name).ok())
+        .map_err(|e| ConfigError::new(path, e))?;
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    parse(extension, &text).map_err(|e| ConfigError::new(path, e))
+}
+
+fn parse<T: DeserializeOwned + Default>(extension: &str, text: &str) -> Result<T, String> {
+    match extension {
+        // yaml and json errors already end with the line and column
+        "yaml" | "yml" => {
+            // an empty or comment only file has no document
+            if serde_yaml::from_str::<serde_yaml::Value>(text).is_ok_and(|v| v.is_null()) {
+                return Ok(T::default());
+            }
+            serde_yaml::from_str(text).map_err(|e| e.to_string())
+        }
+        "json" => serde_json::from_str(text).map_err(|e| e.to_string()),
+        "toml" => toml::from_str(text).map_err(|e| match e.span() {
+            Some(span) => {
+                let (line, column) = line_column(text, span.start);
+                format!("{} at line {} column {}", e.message(), line, column)
+            }
+            None => e.message().to_string(),
+        }),
+        _ => Err(format!(
+            "unsupported config format {:?}, expected one of {:?}",
+            extension, EXTENSIONS
+        )),
+    }
+}
+
+/// One-based line and column of a byte offset
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+    (line, column)
+}
+
+/// Replace `${NAME}` and `${NAME:-default}` with environment variables, `$${` is a literal `${`.
+/// Comment lines are kept as they are, a commented out rule doesn't need its variables
+fn substitute_env(text: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    for (index, line) in text.split_inclusive('\n').enumerate() {
+        if line.trim_start().starts_with('#') {
+            out.push_str(line);
+        } else {
+            substitute_line(&mut out, line, index + 1, &lookup)?;
+        }
+    }
+    Ok(out)
+}
+
+fn substitute_line(
+    out: &mut String,
+    line: &str,
+    number: usize,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> Result<(), String> {
+    let mut rest = line;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        if let Some(escaped) = tail.strip_prefix("$${") {
+            out.push_str("${");
+            rest = escaped;
+            continue;
+        }
+        let Some(expr) = tail.strip_prefix("${") else {
+            out.push('$');
+            rest = &tail[1..];
+            continue;
+        };
+        let end = expr
+            .find('}')
+            .ok_or_else(|| format!("unterminated ${{ at line {}", number))?;
+        let (name, default) = match expr[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&expr[..end], None),
+        };
+        match lookup(name).or_else(|| default.map(str::to_string)) {
+            Some(value) => out.push_str(&value),
+            None => {
+                return Err(format!(
+                    "environment variable {} is not set at line {}",
+                    name, number
+                ))
+            }
+        }
+        rest = &expr[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(())
+}
+
+fn merge(mut files: Vec<(PathBuf, ConfigFile)>) -> Result<StaticConfig, ConfigError> {
+    let mut sets: HashMap<String, (PathBuf, SetConfig)> = HashMap::new();
+    for (path, file) in &mut files {
+        for (name, set) in std::mem::take(&mut file.sets) {
+            if let Some((other, _)) = sets.get(&name) {
+                return Err(ConfigError::new(
+                    path,
+                    format!("set {} is already defined in {}", name, other.display()),
+                ));
+            }
+            sets.insert(name, (path.clone(), set));
+        }
+    }
+    let addresses = |path: &Path, name: &str| match sets.get(name) {
+        Some((_, set)) if !set.addresses.is_empty() => {
+            Ok((set.addresses.clone(), set.description.clone()))
+        }
+        Some(_) => Err(ConfigError::new(
+            path,
+            format!("set {} has no addresses", name),
+        )),
+        None => Err(ConfigError::new(path, format!("unknown set {}", name))),
+    };
+    let ports = |path: &Path, name: &str| match sets.get(name) {
+        Some((_, set)) if !set.ports.is_empty() => Ok((set.ports.clone(), set.description.clone())),
+        Some(_) => Err(ConfigError::new(path, format!("set {} has no ports", name))),
+        None => Err(ConfigError::new(path, format!("unknown set {}", name))),
+    };
+
+    let mut config = StaticConfig::default();
+    let mut sections: HashMap<&str, PathBuf> = HashMap::new();
+    for (path, file) in files {
+        expand(
+            &path,
+            file.source_blacklist,
+            addresses,
+            &mut config.source_blacklist,
+        )?;
+        expand(
+            &path,
+            file.source_whitelist,
+            addresses,
+            &mut config.source_whitelist,
+        )?;
+        expand(
+            &path,
+            file.port_blacklist,
+            ports,
+            &mut config.port_blacklist,
+        )?;
+        for (name, interface) in file.interfaces {
+            let rules = config.interfaces.entry(name).or_default();
+            expand(
+                &path,
+                interface.source_blacklist,
+                addresses,
+                &mut rules.source_blacklist,
+            )?;
+            expand(
+                &path,
+                interface.source_whitelist,
+                addresses,
+                &mut rules.source_whitelist,
+            )?;
+            expand(
+                &path,
+                interface.port_blacklist,
+                ports,
+                &mut rules.port_blacklist,
+            )?;
+        }
+        config
+            .source_whitelist_dns
+            .extend(file.source_whitelist_dns);
+        config.feeds.extend(file.feeds);
+        for name in file.tc_ingress {
+            if !config.tc_ingress.contains(&name) {
+                config.tc_ingress.push(name);
+            }
+        }
+        for (name, program) in file.xdp_chain {
+            if config.xdp_chain.insert(name.clone(), program).is_some() {
+                return Err(ConfigError::new(
+                    &path,
+                    format!("xdp_chain of {} is defined twice", name),
+                ));
+            }
+        }
+        for (name, token) in file.api_tokens {
+            if config.api_tokens.insert(name.clone(), token).is_some() {
+                return Err(ConfigError::new(
+                    &path,
+                    format!("api token {} is defined twice", name),
+                ));
+            }
+        }
+
+        let mut section = |name: &'static str, present: bool| match sections.get(name) {
+            Some(other) if present => Err(ConfigError::new(
+                &path,
+                format!("{} is already defined in {}", name, other.display()),
+            )),
+            _ => {
+                if present {
+                    sections.insert(name, path.clone());
+                }
+                Ok(present)
+            }
+        };
+        if section("tls", file.tls.is_some())? {
+            config.tls = file.tls;
+        }
+        if section("auto_ban", file.auto_ban.is_some())? {
+            config.auto_ban = file.auto_ban;
+        }
+        if section("scan_detection", file.scan_detection.is_some())? {
+            config.scan_detection = file.scan_detection;
+        }
+        if section("trap_ports", file.trap_ports.is_some())? {
+            config.trap_ports = file.trap_ports;
+        }
+        if section("geo", file.geo.is_some())? {
+            config.geo = file.geo;
+        }
+        if section("map_capacities", file.map_capacities.is_some())? {
+            config.map_capacities = file.map_capacities;
+        }
+        if section("xdp_mode", file.xdp_mode.is_some())? {
+            config.xdp_mode = file.xdp_mode;
+        }
+    }
+    Ok(config)
+}
+
+/// Turn the entries of one list into rules, a set entry becomes one rule per member
+fn expand<T: Clone>(
+    path: &Path,
+    entries: Vec<RuleEntry<T>>,
+    members: impl Fn(&Path, &str) -> Result<(Vec<T>, Option<String>), ConfigError>,
+    rules: &mut Vec<Rule<T>>,
+) -> Result<(), ConfigError> {
+    for entry in entries {
+        match entry {
+            RuleEntry::Value(value) => rules.push(Rule {
+                value,
+                description: None,
+                set: None,
+            }),
+            RuleEntry::Rule { value, description } => rules.push(Rule {
+                value,
+                description,
+                set: None,
+            }),
+            RuleEntry::Set { set, description } => {
+                let (values, set_description) = members(path, &set)?;
+                let description = description.or(set_description);
+                rules.extend(values.into_iter().map(|value| Rule {
+                    value,
+                    description: description.clone(),
+                    set: Some(set.clone()),
+                }));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, net::Ipv4Addr};
+
+    use super::{load, parse, substitute_env, ConfigFile};
+
+    #[test]
+    fn env_substitution() {
+        let vars = HashMap::from([("PORT", "22")]);
+        let lookup = |name: &str| vars.get(name).map(|v| v.to_string());
+        assert_eq!(
+            substitute_env("port: ${PORT}, other: ${OTHER:-23}, cost: $5", lookup).unwrap(),
+            "port: 22, other: 23, cost: $5"
+        );
+        assert_eq!(substitute_env("a: $${PORT}", lookup).unwrap(), "a: ${PORT}");
+        assert_eq!(
+            substitute_env("# b: ${MISSING}\n  # ${MISSING}\na: ${PORT}", lookup).unwrap(),
+            "# b: ${MISSING}\n  # ${MISSING}\na: 22"
+        );
+        let error = substitute_env("a: 1\nb: ${MISSING}", lookup).unwrap_err();
+        assert!(
+            error.contains("MISSING") && error.contains("line 2"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn errors_have_lines() {
+        let error = parse::<ConfigFile>(
+            "yaml",
+            "source_blacklist:\n  - 1.2.3.4\nport_blacklist: [x]\n",
+        )
+        .err()
+        .unwrap();
+        assert!(error.contains("line 3"), "{}", error);
+        let error = parse::<ConfigFile>("json", "{\n\"unknown\": 1}")
+            .err()
+            .unwrap();
+        assert!(error.contains("line 2"), "{}", error);
+        assert!(parse::<ConfigFile>("yaml", "# only a comment\n").is_ok());
+    }
+
+    #[test]
+    fn includes_and_sets() {
+        let dir = std::env::temp_dir().join(format!("sdf-config-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+        std::fs::write(
+            dir.join("main.yaml"),
+            "include: [conf.d]\n\
+             sets:\n  partners:\n    description: partner offices\n    addresses: [10.0.0.1, 10.0.0.2]\n\
+             source_whitelist:\n  - 1.1.1.1\n  - set: partners\n\
+             interfaces:\n  eth1:\n    port_blacklist: [443]\n\
+             tc_ingress: [tun0]\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("conf.d/ports.json"),
+            r#"{"sets": {"dns": {"ports": [53, 853]}},
+                "port_blacklist": [{"set": "dns", "description": "no dns"}, 22],
+                "interfaces": {"eth1": {"source_blacklist": [{"set": "partners"}]}},
+                "tc_ingress": ["tun0", "gre1"]}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("conf.d/ignored.txt"), "not a config").unwrap();
+
+        let config = load(&dir.join("main.yaml")).unwrap();
+        let whitelist: Vec<_> = config.source_whitelist.iter().map(|r| r.value).collect();
+        assert_eq!(
+            whitelist,
+            [
+                Ipv4Addr::new(1, 1, 1, 1),
+                Ipv4Addr::new(10, 0, 0, 1),
+                Ipv4Addr::new(10, 0, 0, 2)
+            ]
+        );
+        assert_eq!(
+            config.source_whitelist[1].description.as_deref(),
+            Some("partner offices")
+        );
+        let ports: Vec<_> = config.port_blacklist.iter().map(|r| r.value).collect();
+        assert_eq!(ports, [53, 853, 22]);
+        assert_eq!(config.port_blacklist[0].set.as_deref(), Some("dns"));
+        let eth1 = &config.interfaces["eth1"];
+        assert_eq!(eth1.port_blacklist[0].value, 443);
+        assert_eq!(eth1.source_blacklist.len(), 2);
+        assert_eq!(config.tc_ingress, ["tun0", "gre1"]);
+
+        std::fs::write(
+            dir.join("conf.d/bad.yaml"),
+            "source_blacklist:\n  - set: dns\n",
+        )
+        .unwrap();
+        let error = load(&dir.join("main.yaml")).unwrap_err().to_string();
+        assert!(error.contains("set dns has no addresses"), "{}", error);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    collections::{HashMap as StdHashMap, HashSet},
+    net::Ipv4Addr,
+    time::{Duration, Instant, SystemTime},
+};
+
+use aya::{
+    maps::{HashMap, MapError},
+    Bpf,
+};
+use hickory_resolver::{error::ResolveErrorKind, TokioAsyncResolver};
+use log::{info, warn};
+use poem_openapi::Object;
+use tokio::sync::{mpsc::Sender, watch};
+
+/// Bounds on how long a resolution is trusted, whatever the record TTL says
+const MIN_REFRESH: Duration = Duration::from_secs(5);
+const MAX_REFRESH: Duration = Duration::from_secs(60 * 60);
+/// Delay before retrying a name whose resolution failed
+const RETRY_AFTER: Duration = Duration::from_secs(30);
+/// SRC_WHITELIST value of the resolved addresses, the rules of the config file and the API
+/// are 0. Only userspace reads it, to find them again in pinned maps
+const DNS_OWNED: u8 = 1;
+
+/// Addresses of one name, sent by the resolver task to the main loop
+pub struct DnsUpdate {
+    pub name: String,
+    /// Empty when the name doesn't exist anymore
+    pub result: Result<HashSet<u32>, String>,
+}
+
+async fn lookup(
+    resolver: &TokioAsyncResolver,
+    name: &str,
+) -> (Result<HashSet<u32>, String>, Duration) {
+    match resolver.ipv4_lookup(name).await {
+        Ok(lookup) => {
+            let addresses = lookup.iter().map(|a| u32::from(a.0)).collect();
+            let ttl = lookup
+                .valid_until()
+                .saturating_duration_since(Instant::now())
+                .clamp(MIN_REFRESH, MAX_REFRESH);
+            (Ok(addresses), ttl)
+        }
+        Err(e) => match e.kind() {
+            ResolveErrorKind::NoRecordsFound { negative_ttl, .. } => {
+                let ttl = negative_ttl
+                    .map(|ttl| Duration::from_secs(ttl as u64))
+                    .unwrap_or(RETRY_AFTER)
+                    .clamp(MIN_REFRESH, MAX_REFRESH);
+                (Ok(HashSet::new()), ttl)
+            }
+            _ => (Err(e.to_string()), RETRY_AFTER),
+        },
+    }
+}
+
+/// Resolve every name again when its records expire, the names are replaced on reload
+pub fn spawn_dns_resolver(
+    mut names: watch::Receiver<Vec<String>>,
+    tx: Sender<DnsUpdate>,
+) -> Result<(), anyhow::Error> {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+    tokio::spawn(async move {
+        let mut next: StdHashMap<String, Instant> = StdHashMap::new();
+        loop {
+            let wanted = names.borrow_and_update().clone();
+            next.retain(|name, _| wanted.contains(name));
+            for name in wanted {
+                next.entry(name).or_insert_with(Instant::now);
+            }
+
+            let now = Instant::now();
+            let due: Vec<String> = next
+                .iter()
+                .filter(|(_, at)| **at <= now)
+                .map(|(name, _)| name.clone())
+                .collect();
+            for name in due {
+                let (result, ttl) = lookup(&resolver, &name).await;
+                next.insert(name.clone(), Instant::now() + ttl);
+                if tx.send(DnsUpdate { name, result }).await.is_err() {
+                    return;
+                }
+            }
+
+            let sleep_until = next.values().min().copied().unwrap_or(now + MAX_REFRESH);
+            tokio::select! {
+                _ = tokio::time::sleep_until(sleep_until.into()) => {}
+                changed = names.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[derive(Object, Debug, Clone)]
+pub struct DnsRuleStatus {
+    pub name: String,
+    /// Addresses currently whitelisted for the name
+    pub addresses: Vec<String>,
+    /// RFC 3339 time of the last resolution
+    pub last_refresh: Option<String>,
+    /// Error of the last resolution, the previous addresses stay whitelisted
+    pub error: Option<String>,
+}
+
+/// Addresses of SRC_WHITELIST that come from DNS names
+#[derive(Default)]
+pub struct DnsAllow {
+    status: Vec<DnsRuleStatus>,
+    addresses: StdHashMap<String, HashSet<u32>>,
+    /// Entries inserted by the resolver, static rules with the same address are left alone
+    owned: HashSet<u32>,
+    /// Owned entries a previous run left in the pinned map, removed once every name resolved
+    /// unless one of them still resolves to it
+    adopted: HashSet<u32>,
+}
+
+impl DnsAllow {
+    pub fn status(&self) -> Vec<DnsRuleStatus> {
+        self.status.clone()
+    }
+
+    /// Addresses of SRC_WHITELIST the resolver inserted
+    pub fn owned(&self) -> impl Iterator<Item = u32> + '_ {
+        self.owned.iter().copied()
+    }
+
+    /// Take the names of a reloaded config and put the resolved addresses back after
+    /// the reload emptied SRC_WHITELIST
+    pub fn reload(&mut self, bpf: &mut Bpf, names: &[String]) -> Result<(), MapError> {
+        self.status.retain(|status| names.contains(&status.name));
+        self.addresses.retain(|name, _| names.contains(name));
+        for name in names {
+            if !self.status.iter().any(|status| &status.name == name) {
+                self.status.push(DnsRuleStatus {
+                    name: name.clone(),
+                    addresses: vec![],
+                    last_refresh: None,
+                    error: None,
+                });
+            }
+        }
+
+        let mut whitelist: HashMap<_, u32, u8> =
+            HashMap::try_from(bpf.map_mut("SRC_WHITELIST").unwrap())?;
+        self.owned.clear();
+        self.adopted.clear();
+        for ip in self.addresses.values().flatten() {
+            if whitelist.get(ip, 0).is_err() {
+                whitelist.insert(*ip, DNS_OWNED, 0)?;
+                self.owned.insert(*ip);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whitelist the new addresses of a name and remove the ones no name resolves to anymore
+    pub fn apply(&mut self, bpf: &mut Bpf, update: DnsUpdate) -> Result<(), MapError> {
+        let Some(status) = self.status.iter_mut().find(|s| s.name == update.name) else {
+            return Ok(());
+        };
+        status.last_refresh =
+            Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string());
+        let resolved = match update.result {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                warn!("resolve {} error {}", update.name, e);
+                status.error = Some(e);
+                return Ok(());
+            }
+        };
+        status.error = None;
+
+        let old = self.addresses.remove(&update.name).unwrap_or_default();
+        let mut whitelist: HashMap<_, u32, u8> =
+            HashMap::try_from(bpf.map_mut("SRC_WHITELIST").unwrap())?;
+        for ip in old.difference(&resolved) {
+            let still_resolved = self.addresses.values().any(|ips| ips.contains(ip));
+            if !still_resolved && self.owned.remove(ip) {
+                let _ = whitelist.remove(ip);
+                info!(
+                    "removed {} of {} from source whitelist",
+                    Ipv4Addr::from(*ip),
+                    update.name
+                );
+            }
+        }
+        let mut loaded = HashSet::new();
+        for ip in resolved.difference(&old) {
+            if whitelist.get(ip, 0).is_ok() {
+                loaded.insert(*ip);
+                continue;
+            }
+            match whitelist.insert(*ip, DNS_OWNED, 0) {
+                Ok(()) => {
+                    self.owned.insert(*ip);
+                    loaded.insert(*ip);
+                    info!(
+                        "added {} of {} to source whitelist",
+                        Ipv4Addr::from(*ip),
+                        update.name
+                    );
+                }
+                Err(e) => {
+                    warn!("add {} of {} error {}", Ipv4Addr::from(*ip), update.name, e);
+                    status.error = Some(format!("add {} error {}", Ipv4Addr::from(*ip), e));
+                }
+            }
+        }
+        loaded.extend(resolved.intersection(&old));
+
+        let mut addresses: Vec<_> = loaded.iter().map(|ip| Ipv4Addr::from(*ip)).collect();
+        addresses.sort();
+        status.addresses = addresses.into_iter().map(|ip| ip.to_string()).collect();
+        self.addresses.insert(update.name, loaded);
+
+        let resolved_all = self
+            .status
+            .iter()
+            .all(|status| status.last_refresh.is_some() && status.error.is_none());
+        if resolved_all {
+            for ip in std::mem::take(&mut self.adopted) {
+                let still_resolved = self.addresses.values().any(|ips| ips.contains(&ip));
+                if !still_resolved && self.owned.remove(&ip) {
+                    let _ = whitelist.remove(&ip);
+                    info!(
+                        "removed {} of the previous run from source whitelist",
+                        Ipv4Addr::from(ip)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Take over the resolved addresses a previous run left in the pinned SRC_WHITELIST
+    pub fn adopt(&mut self, bpf: &mut Bpf) -> Result<(), MapError> {
+        let mut whitelist: HashMap<_, u32, u8> =
+            HashMap::try_from(bpf.map_mut("SRC_WHITELIST").unwrap())?;
+        let pinned: Vec<u32> = whitelist
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|(_, value)| *value == DNS_OWNED)
+            .map(|(ip, _)| ip)
+            .collect();
+        // no name is left to resolve them again
+        if self.status.is_empty() {
+            for ip in &pinned {
+                let _ = whitelist.remove(ip);
+            }
+            return Ok(());
+        }
+        for ip in pinned {
+            if self.owned.insert(ip) {
+                self.adopted.insert(ip);
+            }
+        }
+        if !self.adopted.is_empty() {
+            info!(
+                "{} dns whitelist addresses kept from the previous run",
+                self.adopted.len()
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    collections::VecDeque,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::SystemTime,
+};
+
+use aya::{maps::AsyncPerfEventArray, util::online_cpus, Bpf};
+use bytes::BytesMut;
+use log::warn;
+use poem_openapi::Object;
+use sdf_common::{DropEvent, DropReason};
+use tokio::sync::broadcast;
+
+/// Number of events kept for the query endpoint
+const RECENT_CAPACITY: usize = 1000;
+/// Events buffered for each live subscriber before it starts lagging
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Object, Debug, Clone)]
+pub struct DropEventInfo {
+    /// Increasing number, used to poll only newer events
+    pub seq: u64,
+    /// RFC 3339 time at which userspace received the event
+    pub timestamp: String,
+    pub source: String,
+    pub source_port: u16,
+    pub dest: String,
+    pub dest_port: u16,
+    pub reason: String,
+    /// Geo rule that matched the source, like `country:CN`
+    pub matched: Option<String>,
+}
+
+/// Fan out of the drop events read from the datapath
+pub struct EventHub {
+    tx: broadcast::Sender<DropEventInfo>,
+    recent: Mutex<VecDeque<DropEventInfo>>,
+    seq: AtomicU64,
+    /// Names of the geo rules, indexed like `DropEvent::rule`
+    geo_rules: RwLock<Vec<String>>,
+}
+
+impl Default for EventHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventHub {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            tx,
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_CAPACITY)),
+            seq: AtomicU64::new(0),
+            geo_rules: RwLock::new(vec![]),
+        }
+    }
+
+    pub fn set_geo_rules(&self, names: Vec<String>) {
+        *self.geo_rules.write().expect("Should lock") = names;
+    }
+
+    fn push(&self, event: &DropEvent) {
+        let reason = DropReason::from_u8(event.reason);
+        let matched = match reason {
+            Some(DropReason::GeoBlacklist) => {
+                let geo_rules = self.geo_rules.read().expect("Should lock");
+                geo_rules.get(event.rule as usize).cloned()
+            }
+            _ => None,
+        };
+        let info = DropEventInfo {
+            seq: self.seq.fetch_add(1, Ordering::Relaxed) + 1,
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            source: Ipv4Addr::from(event.source).to_string(),
+            source_port: event.source_port,
+            dest: Ipv4Addr::from(event.dest).to_string(),
+            dest_port: event.dest_port,
+            reason: reason.map(|r| r.as_str()).unwrap_or("unknown").to_string(),
+            matched,
+        };
+
+        let mut recent = self.recent.lock().expect("Should lock");
+        if recent.len() == RECENT_CAPACITY {
+            recent.pop_front();
+        }
+        recent.push_back(info.clone());
+        drop(recent);
+
+        // no subscriber is not an error
+        let _ = self.tx.send(info);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<DropEventInfo> {
+        self.tx.subscribe()
+    }
+
+    /// Up to `limit` newest events with a sequence number above `after`, oldest first
+    pub fn recent(&self, after: u64, limit: usize) -> Vec<DropEventInfo> {
+        let recent = self.recent.lock().expect("Should lock");
+        let newer: Vec<_> = recent.iter().filter(|e| e.seq > after).collect();
+        let skip = newer.len().saturating_sub(limit);
+        newer.into_iter().skip(skip).cloned().collect()
+    }
+}
+
+/// Read the DROP_EVENTS perf array of every cpu into the hub
+pub fn spawn_drop_event_readers(bpf: &mut Bpf, hub: Arc<EventHub>) -> Result<(), anyhow::Error> {
+    let mut perf_array = AsyncPerfEventArray::try_from(bpf.take_map("DROP_EVENTS").unwrap())?;
+    for cpu_id in online_cpus()? {
+        let mut buf = perf_array.open(cpu_id, None)?;
+        let hub = hub.clone();
+        tokio::spawn(async move {
+            let mut buffers = (0..10)
+                .map(|_| BytesMut::with_capacity(std::mem::size_of::<DropEvent>()))
+                .collect::<Vec<_>>();
+            loop {
+                let events = match buf.read_events(&mut buffers).await {
+                    Ok(events) => events,
+                    Err(e) => {
+                        warn!("read drop events on cpu {} error {}", cpu_id, e);
+                        break;
+                    }
+                };
+                if events.lost > 0 {
+                    warn!("lost {} drop events on cpu {}", events.lost, cpu_id);
+                }
+                for buf in buffers.iter().take(events.read) {
+                    let event = unsafe { (buf.as_ptr() as *const DropEvent).read_unaligned() };
+                    hub.push(&event);
+                }
+            }
+        });
+    }
+    Ok(())
+}
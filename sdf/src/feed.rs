@@ -0,0 +1,318 @@
+use std::{
+    collections::{HashMap as StdHashMap, HashSet},
+    net::Ipv4Addr,
+    time::{Duration, SystemTime},
+};
+
+use aya::{
+    maps::{
+        lpm_trie::{Key, LpmTrie},
+        MapError,
+    },
+    Bpf,
+};
+use log::{info, warn};
+use poem_openapi::Object;
+use tokio::sync::mpsc::Sender;
+
+use crate::config::FeedConfig;
+
+/// Limits of one download, a stalled server would otherwise block the refreshes of its feed
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// IPv4 prefix in host byte order, the address is masked to the prefix length
+pub type Prefix = (u32, u8);
+
+/// Parse one line of a feed, None for comments, headers and unsupported entries.
+///
+/// Handles plain IP or CIDR lists with `;` or `#` comments, like Spamhaus DROP or FireHOL,
+/// and `ipset save` output.
+pub fn parse_line(line: &str) -> Option<Result<Prefix, String>> {
+    let line = line.split([';', '#']).next().unwrap_or_default().trim();
+    let mut tokens = line.split_whitespace();
+    let entry = match tokens.next()? {
+        "create" => return None,
+        "add" => tokens.nth(1)?,
+        entry => entry,
+    };
+    Some(parse_prefix(entry))
+}
+
+fn parse_prefix(entry: &str) -> Result<Prefix, String> {
+    let (addr, len) = match entry.split_once('/') {
+        Some((addr, len)) => (
+            addr,
+            len.parse::<u8>()
+                .ok()
+                .filter(|len| *len <= 32)
+                .ok_or_else(|| format!("invalid prefix length in {}", entry))?,
+        ),
+        None => (entry, 32),
+    };
+    let addr = addr
+        .parse::<Ipv4Addr>()
+        .map_err(|_| format!("invalid ipv4 address {}", entry))?;
+    let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or_default();
+    Ok((u32::from(addr) & mask, len))
+}
+
+/// Result of one refresh, sent by the refresh task to the main loop
+pub struct FeedUpdate {
+    pub name: String,
+    pub result: Result<ParsedFeed, String>,
+}
+
+pub struct ParsedFeed {
+    pub prefixes: HashSet<Prefix>,
+    /// Lines that looked like an entry but couldn't be parsed, like IPv6 addresses
+    pub invalid_lines: usize,
+}
+
+pub fn parse_feed(content: &str) -> ParsedFeed {
+    let mut feed = ParsedFeed {
+        prefixes: HashSet::new(),
+        invalid_lines: 0,
+    };
+    for line in content.lines() {
+        match parse_line(line) {
+            Some(Ok(prefix)) => {
+                feed.prefixes.insert(prefix);
+            }
+            Some(Err(_)) => feed.invalid_lines += 1,
+            None => {}
+        }
+    }
+    feed
+}
+
+async fn fetch(client: &reqwest::Client, source: &str) -> Result<String, String> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        client
+            .get(source)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|e| e.to_string())?
+            .text()
+            .await
+            .map_err(|e| e.to_string())
+    } else {
+        tokio::fs::read_to_string(source)
+            .await
+            .map_err(|e| format!("read {} error {}", source, e))
+    }
+}
+
+/// Fetch and parse every feed on its schedule, the main loop applies the updates
+pub fn spawn_feed_refresh(feeds: Vec<FeedConfig>, tx: Sender<FeedUpdate>) {
+    let client = match reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("create feed http client error {}, feeds are disabled", e);
+            return;
+        }
+    };
+    for feed in feeds {
+        let tx = tx.clone();
+        let client = client.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(feed.refresh);
+            loop {
+                interval.tick().await;
+                let result = fetch(&client, &feed.source)
+                    .await
+                    .map(|content| parse_feed(&content));
+                let update = FeedUpdate {
+                    name: feed.name.clone(),
+                    result,
+                };
+                if tx.send(update).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[derive(Object, Debug, Clone)]
+pub struct FeedStatus {
+    pub name: String,
+    pub source: String,
+    /// Prefixes of the feed currently loaded
+    pub rules: usize,
+    pub invalid_lines: usize,
+    /// RFC 3339 time of the last refresh attempt, and of the last one that worked
+    pub last_refresh: Option<String>,
+    pub last_success: Option<String>,
+    /// Error of the last refresh, the previous rules stay loaded
+    pub error: Option<String>,
+}
+
+/// Content of FEED_BLACKLIST, a prefix is in the map as long as one feed lists it
+pub struct Feeds {
+    status: Vec<FeedStatus>,
+    prefixes: StdHashMap<String, HashSet<Prefix>>,
+    refcount: StdHashMap<Prefix, u32>,
+    /// Prefixes found in the pinned map at startup, removed once every feed refreshed
+    /// unless one still lists them
+    pinned: HashSet<Prefix>,
+}
+
+impl Feeds {
+    pub fn new(feeds: &[FeedConfig]) -> Self {
+        Self {
+            status: feeds
+                .iter()
+                .map(|feed| FeedStatus {
+                    name: feed.name.clone(),
+                    source: feed.source.clone(),
+                    rules: 0,
+                    invalid_lines: 0,
+                    last_refresh: None,
+                    last_success: None,
+                    error: None,
+                })
+                .collect(),
+            prefixes: StdHashMap::new(),
+            refcount: StdHashMap::new(),
+            pinned: HashSet::new(),
+        }
+    }
+
+    /// Take over the prefixes a previous run left in the pinned FEED_BLACKLIST, they keep
+    /// blocking until the feeds are fetched again
+    pub fn adopt(&mut self, bpf: &mut Bpf) -> Result<(), MapError> {
+        let mut trie: LpmTrie<_, u32, u8> =
+            LpmTrie::try_from(bpf.map_mut("FEED_BLACKLIST").unwrap())?;
+        let keys: Vec<Key<u32>> = trie.keys().collect::<Result<_, _>>()?;
+        if self.status.is_empty() {
+            for key in &keys {
+                let _ = trie.remove(key);
+            }
+            return Ok(());
+        }
+        self.pinned = keys
+            .iter()
+            .map(|key| (u32::from_be(key.data()), key.prefix_len() as u8))
+            .collect();
+        info!(
+            "{} feed prefixes kept from the previous run",
+            self.pinned.len()
+        );
+        Ok(())
+    }
+
+    pub fn status(&self) -> Vec<FeedStatus> {
+        self.status.clone()
+    }
+
+    /// Apply only the prefixes added to or removed from the feed since its last refresh
+    pub fn apply(&mut self, bpf: &mut Bpf, update: FeedUpdate) -> Result<(), MapError> {
+        let Some(status) = self.status.iter_mut().find(|s| s.name == update.name) else {
+            return Ok(());
+        };
+        let now = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+        status.last_refresh = Some(now.clone());
+        let feed = match update.result {
+            Ok(feed) => feed,
+            Err(e) => {
+                warn!("refresh feed {} error {}", update.name, e);
+                status.error = Some(e);
+                return Ok(());
+            }
+        };
+
+        let mut trie: LpmTrie<_, u32, u8> =
+            LpmTrie::try_from(bpf.map_mut("FEED_BLACKLIST").unwrap())?;
+        let old = self.prefixes.remove(&update.name).unwrap_or_default();
+        let mut loaded = HashSet::new();
+        let (mut added, mut removed, mut failed) = (0, 0, 0);
+
+        for prefix in old.difference(&feed.prefixes) {
+            let count = self.refcount.entry(*prefix).or_default();
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                self.refcount.remove(prefix);
+                let _ = trie.remove(&key(prefix));
+                removed += 1;
+            }
+        }
+        for prefix in &feed.prefixes {
+            if old.contains(prefix) {
+                loaded.insert(*prefix);
+                continue;
+            }
+            let count = self.refcount.entry(*prefix).or_default();
+            if *count == 0 && trie.insert(&key(prefix), 0, 0).is_err() {
+                // retried on the next refresh
+                failed += 1;
+                continue;
+            }
+            *count += 1;
+            loaded.insert(*prefix);
+            added += 1;
+        }
+        self.refcount.retain(|_, count| *count > 0);
+        status.rules = loaded.len();
+        status.invalid_lines = feed.invalid_lines;
+        status.last_success = Some(now);
+        status.error = (failed > 0).then(|| format!("{} prefixes didn't fit in the map", failed));
+
+        if !self.pinned.is_empty() && self.status.iter().all(|s| s.last_success.is_some()) {
+            for prefix in self.pinned.drain() {
+                if !self.refcount.contains_key(&prefix) {
+                    let _ = trie.remove(&key(&prefix));
+                    removed += 1;
+                }
+            }
+        }
+
+        info!(
+            "refreshed feed {}: {} added, {} removed, {} failed",
+            update.name, added, removed, failed
+        );
+        self.prefixes.insert(update.name, loaded);
+        Ok(())
+    }
+}
+
+fn key(prefix: &Prefix) -> Key<u32> {
+    Key::new(prefix.1 as u32, prefix.0.to_be())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_feed, parse_line};
+
+    #[test]
+    fn parse_formats() {
+        assert_eq!(parse_line("1.2.3.4"), Some(Ok((0x01020304, 32))));
+        assert_eq!(
+            parse_line("1.2.3.0/24 ; SBL123"),
+            Some(Ok((0x01020300, 24)))
+        );
+        assert_eq!(parse_line("1.2.3.4/16"), Some(Ok((0x01020000, 16))));
+        assert_eq!(
+            parse_line("add blocklist 10.0.0.0/8"),
+            Some(Ok((0x0a000000, 8)))
+        );
+        assert_eq!(parse_line("create blocklist hash:net family inet"), None);
+        assert_eq!(parse_line("; Spamhaus DROP List"), None);
+        assert_eq!(parse_line("# firehol_level1"), None);
+        assert_eq!(parse_line("   "), None);
+        assert!(matches!(parse_line("2001:db8::/32"), Some(Err(_))));
+        assert!(matches!(parse_line("1.2.3.4/33"), Some(Err(_))));
+    }
+
+    #[test]
+    fn parse_whole_feed() {
+        let feed = parse_feed("; header\n1.2.3.4\n1.2.3.4/32\nbad\n10.0.0.0/8 # comment\n");
+        assert_eq!(feed.prefixes.len(), 2);
+        assert_eq!(feed.invalid_lines, 1);
+    }
+}
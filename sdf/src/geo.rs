@@ -0,0 +1,469 @@
+use std::{
+    collections::HashMap as StdHashMap,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use aya::{
+    maps::{
+        lpm_trie::{Key, LpmTrie},
+        Array, HashMap, MapError,
+    },
+    Bpf,
+};
+use ipnetwork::IpNetwork;
+use log::{info, warn};
+use maxminddb::{geoip2, Reader};
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+    config::{GeoConfig, GeoRuleConfig},
+    feed::Prefix,
+};
+
+/// Rules are bits of the GEO_BLACKLIST values
+pub const MAX_GEO_RULES: usize = 64;
+/// How often the database files are checked for changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Prefixes resolved from the databases, sent by the resolver task to the main loop
+pub struct GeoUpdate {
+    pub result: Result<StdHashMap<Prefix, u64>, String>,
+}
+
+pub fn validate(config: &GeoConfig) -> Result<(), String> {
+    if config.rules.len() > MAX_GEO_RULES {
+        return Err(format!("at most {} geo rules are supported", MAX_GEO_RULES));
+    }
+    for rule in &config.rules {
+        match (&rule.country, rule.asn) {
+            (Some(_), None) if config.country_db.is_none() => {
+                return Err("country rules need country_db".to_string())
+            }
+            (None, Some(_)) if config.asn_db.is_none() => {
+                return Err("asn rules need asn_db".to_string())
+            }
+            (Some(_), None) | (None, Some(_)) => {}
+            _ => return Err("a geo rule needs exactly one of country and asn".to_string()),
+        }
+    }
+    Ok(())
+}
+
+/// Database files that aren't there, they must be at startup
+pub fn missing_databases(config: &GeoConfig) -> Vec<&PathBuf> {
+    [&config.country_db, &config.asn_db]
+        .into_iter()
+        .flatten()
+        .filter(|path| !path.is_file())
+        .collect()
+}
+
+/// Bitmask of the rules that each IPv4 prefix of the databases matches
+fn resolve(config: &GeoConfig) -> Result<StdHashMap<Prefix, u64>, String> {
+    let all = IpNetwork::V4("0.0.0.0/0".parse().unwrap());
+    let mut matched: StdHashMap<Prefix, u64> = StdHashMap::new();
+    let mut add = |net: IpNetwork, mask: u64| {
+        if let (IpNetwork::V4(net), true) = (net, mask != 0) {
+            *matched
+                .entry((u32::from(net.network()), net.prefix()))
+                .or_default() |= mask;
+        }
+    };
+
+    if let Some(path) = &config.country_db {
+        let reader =
+            Reader::open_readfile(path).map_err(|e| format!("open {:?} error {}", path, e))?;
+        for item in reader
+            .within::<geoip2::Country>(all)
+            .map_err(|e| e.to_string())?
+        {
+            let item = item.map_err(|e| e.to_string())?;
+            let Some(code) = item.info.country.and_then(|c| c.iso_code) else {
+                continue;
+            };
+            let mask = rule_mask(config, |rule| {
+                rule.country
+                    .as_ref()
+                    .map_or(false, |country| country.eq_ignore_ascii_case(code))
+            });
+            add(item.ip_net, mask);
+        }
+    }
+
+    if let Some(path) = &config.asn_db {
+        let reader =
+            Reader::open_readfile(path).map_err(|e| format!("open {:?} error {}", path, e))?;
+        for item in reader
+            .within::<geoip2::Asn>(all)
+            .map_err(|e| e.to_string())?
+        {
+            let item = item.map_err(|e| e.to_string())?;
+            let Some(asn) = item.info.autonomous_system_number else {
+                continue;
+            };
+            add(item.ip_net, rule_mask(config, |rule| rule.asn == Some(asn)));
+        }
+    }
+
+    Ok(propagate(&matched))
+}
+
+/// The LPM map only returns the longest match, so a prefix carries the rules of the ones containing it
+fn propagate(matched: &StdHashMap<Prefix, u64>) -> StdHashMap<Prefix, u64> {
+    let mut prefixes = matched.clone();
+    for ((addr, len), mask) in prefixes.iter_mut() {
+        for shorter in 0..*len {
+            let net = addr
+                & u32::MAX
+                    .checked_shl(32 - shorter as u32)
+                    .unwrap_or_default();
+            if let Some(outer) = matched.get(&(net, shorter)) {
+                *mask |= outer;
+            }
+        }
+    }
+    prefixes
+}
+
+fn rule_mask(config: &GeoConfig, matches: impl Fn(&GeoRuleConfig) -> bool) -> u64 {
+    config
+        .rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| matches(rule))
+        .fold(0, |mask, (id, _)| mask | 1 << id)
+}
+
+/// Rules applying to every port, and the rules of each listed port
+fn port_masks(config: &GeoConfig) -> (u64, StdHashMap<u16, u64>) {
+    let mut all_ports = 0u64;
+    let mut by_port: StdHashMap<u16, u64> = StdHashMap::new();
+    for (id, rule) in config.rules.iter().enumerate() {
+        if rule.ports.is_empty() {
+            all_ports |= 1 << id;
+        }
+        for port in &rule.ports {
+            *by_port.entry(*port).or_default() |= 1 << id;
+        }
+    }
+    (all_ports, by_port)
+}
+
+fn last_modified(config: &GeoConfig) -> Option<SystemTime> {
+    [&config.country_db, &config.asn_db]
+        .into_iter()
+        .flatten()
+        .filter_map(|path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .max()
+}
+
+/// Resolve the rules at startup and again every time a database file changes, a failed
+/// resolution isn't retried until then
+pub fn spawn_geo_resolver(config: GeoConfig, tx: Sender<GeoUpdate>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        let mut resolved = None;
+        loop {
+            interval.tick().await;
+            let modified = last_modified(&config);
+            if resolved == Some(modified) {
+                continue;
+            }
+            resolved = Some(modified);
+
+            let config = config.clone();
+            let result = tokio::task::spawn_blocking(move || resolve(&config))
+                .await
+                .unwrap_or_else(|e| Err(e.to_string()));
+            if tx.send(GeoUpdate { result }).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Empty the pinned geo maps of a previous run when geo blocking is no longer configured
+pub fn clear(bpf: &mut Bpf) -> Result<(), MapError> {
+    let mut geo_all_ports: Array<_, u64> = Array::try_from(bpf.map_mut("GEO_ALL_PORTS").unwrap())?;
+    geo_all_ports.set(0, 0, 0)?;
+    let mut geo_ports: HashMap<_, u16, u64> = HashMap::try_from(bpf.map_mut("GEO_PORTS").unwrap())?;
+    let ports: Vec<u16> = geo_ports.keys().flatten().collect();
+    for port in ports {
+        let _ = geo_ports.remove(&port);
+    }
+    let mut trie: LpmTrie<_, u32, u64> = LpmTrie::try_from(bpf.map_mut("GEO_BLACKLIST").unwrap())?;
+    let keys: Vec<Key<u32>> = trie.keys().collect::<Result<_, _>>()?;
+    for key in &keys {
+        let _ = trie.remove(key);
+    }
+    Ok(())
+}
+
+/// Content of GEO_BLACKLIST
+pub struct Geo {
+    prefixes: StdHashMap<Prefix, u64>,
+}
+
+impl Geo {
+    /// Write which rules apply to which service ports, they don't change until restart.
+    /// Prefixes left in a pinned GEO_BLACKLIST keep blocking until the first resolution
+    pub fn new(bpf: &mut Bpf, config: &GeoConfig) -> Result<Self, MapError> {
+        let (all_ports, by_port) = port_masks(config);
+        let mut geo_all_ports: Array<_, u64> =
+            Array::try_from(bpf.map_mut("GEO_ALL_PORTS").unwrap())?;
+        geo_all_ports.set(0, all_ports, 0)?;
+        let mut geo_ports: HashMap<_, u16, u64> =
+            HashMap::try_from(bpf.map_mut("GEO_PORTS").unwrap())?;
+        let stale: Vec<u16> = geo_ports
+            .keys()
+            .flatten()
+            .filter(|port| !by_port.contains_key(port))
+            .collect();
+        for port in stale {
+            let _ = geo_ports.remove(&port);
+        }
+        for (port, mask) in by_port {
+            geo_ports.insert(port, mask, 0)?;
+        }
+
+        let trie: LpmTrie<_, u32, u64> = LpmTrie::try_from(bpf.map("GEO_BLACKLIST").unwrap())?;
+        let prefixes = trie
+            .iter()
+            .map(|entry| {
+                entry.map(|(key, mask)| ((u32::from_be(key.data()), key.prefix_len() as u8), mask))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { prefixes })
+    }
+
+    /// Apply only the prefixes that changed since the previous resolution
+    pub fn apply(&mut self, bpf: &mut Bpf, update: GeoUpdate) -> Result<(), MapError> {
+        let mut prefixes = match update.result {
+            Ok(prefixes) => prefixes,
+            Err(e) => {
+                warn!(
+                    "resolve geo rules error {}, keeping the previous prefixes",
+                    e
+                );
+                return Ok(());
+            }
+        };
+
+        let mut trie: LpmTrie<_, u32, u64> =
+            LpmTrie::try_from(bpf.map_mut("GEO_BLACKLIST").unwrap())?;
+        for prefix in self.prefixes.keys() {
+            if !prefixes.contains_key(prefix) {
+                let _ = trie.remove(&key(prefix));
+            }
+        }
+        // prefixes that don't fit are retried on the next resolution
+        let mut failed = vec![];
+        for (prefix, mask) in &prefixes {
+            if self.prefixes.get(prefix) != Some(mask)
+                && trie.insert(&key(prefix), *mask, 0).is_err()
+            {
+                failed.push(*prefix);
+            }
+        }
+        for prefix in &failed {
+            prefixes.remove(prefix);
+        }
+        info!(
+            "loaded {} geo prefixes, {} didn't fit in the map",
+            prefixes.len(),
+            failed.len()
+        );
+        self.prefixes = prefixes;
+        Ok(())
+    }
+}
+
+fn key(prefix: &Prefix) -> Key<u32> {
+    Key::new(prefix.1 as u32, prefix.0.to_be())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{missing_databases, port_masks, propagate, resolve, validate};
+    use crate::config::{GeoConfig, GeoRuleConfig};
+
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    fn map(len: u8) -> Vec<u8> {
+        vec![0xe0 | len]
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        [&[0x40 | value.len() as u8][..], value.as_bytes()].concat()
+    }
+
+    fn uint(value: u32) -> Vec<u8> {
+        [&[0xc4][..], &value.to_be_bytes()].concat()
+    }
+
+    /// MaxMind DB with 24 bit records of disjoint IPv4 prefixes, each with its encoded data
+    fn mmdb(prefixes: &[((u32, u8), Vec<u8>)]) -> Vec<u8> {
+        let mut nodes = vec![[Record::Empty, Record::Empty]];
+        let mut data: Vec<u8> = vec![];
+        for ((addr, len), value) in prefixes {
+            let mut node = 0;
+            for bit in 0..*len {
+                let side = (addr >> (31 - bit) & 1) as usize;
+                if bit + 1 == *len {
+                    nodes[node][side] = Record::Data(data.len());
+                } else if let Record::Node(next) = nodes[node][side] {
+                    node = next;
+                } else {
+                    nodes.push([Record::Empty, Record::Empty]);
+                    nodes[node][side] = Record::Node(nodes.len() - 1);
+                    node = nodes.len() - 1;
+                }
+            }
+            data.extend(value);
+        }
+
+        let count = nodes.len();
+        let mut db = vec![];
+        for record in nodes.iter().flatten() {
+            let value = match record {
+                Record::Empty => count,
+                Record::Node(node) => *node,
+                Record::Data(offset) => count + 16 + offset,
+            };
+            db.extend(&(value as u32).to_be_bytes()[1..]);
+        }
+        db.extend([0; 16]);
+        db.extend(data);
+        db.extend(b"\xab\xcd\xefMaxMind.com");
+        db.extend(map(9));
+        for (key, value) in [
+            ("binary_format_major_version", uint(2)),
+            ("binary_format_minor_version", uint(0)),
+            ("build_epoch", uint(0)),
+            ("database_type", string("sdf-test")),
+            ("description", map(0)),
+            ("ip_version", uint(4)),
+            ("languages", vec![0, 4]),
+            ("node_count", uint(count as u32)),
+            ("record_size", uint(24)),
+        ] {
+            db.extend(string(key));
+            db.extend(value);
+        }
+        db
+    }
+
+    fn country(code: &str) -> Vec<u8> {
+        [
+            map(1),
+            string("country"),
+            map(1),
+            string("iso_code"),
+            string(code),
+        ]
+        .concat()
+    }
+
+    fn asn(number: u32) -> Vec<u8> {
+        [map(1), string("autonomous_system_number"), uint(number)].concat()
+    }
+
+    fn rule(country: Option<&str>, asn: Option<u32>, ports: &[u16]) -> GeoRuleConfig {
+        GeoRuleConfig {
+            country: country.map(str::to_string),
+            asn,
+            ports: ports.to_vec(),
+        }
+    }
+
+    #[test]
+    fn resolve_databases() {
+        let dir = std::env::temp_dir().join(format!("sdf-geo-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("country.mmdb"),
+            mmdb(&[
+                ((0x01000000, 8), country("CN")),
+                ((0x02000000, 8), country("US")),
+            ]),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("asn.mmdb"),
+            mmdb(&[
+                ((0x01020300, 24), asn(64500)),
+                ((0x03000000, 16), asn(64501)),
+            ]),
+        )
+        .unwrap();
+
+        let config = GeoConfig {
+            country_db: Some(dir.join("country.mmdb")),
+            asn_db: Some(dir.join("asn.mmdb")),
+            rules: vec![
+                rule(Some("cn"), None, &[]),
+                rule(None, Some(64500), &[22]),
+                rule(None, Some(64501), &[]),
+            ],
+        };
+        assert_eq!(validate(&config), Ok(()));
+        assert!(missing_databases(&config).is_empty());
+        let prefixes = resolve(&config).unwrap();
+        assert_eq!(
+            prefixes,
+            HashMap::from([
+                ((0x01000000, 8), 0b001),
+                ((0x01020300, 24), 0b011),
+                ((0x03000000, 16), 0b100),
+            ])
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(validate(&config), Ok(()));
+        assert_eq!(missing_databases(&config).len(), 2);
+        assert!(resolve(&config).is_err());
+    }
+
+    #[test]
+    fn nested_prefixes() {
+        let matched = HashMap::from([
+            ((0x0a000000, 8), 0b001),
+            ((0x0a010000, 16), 0b010),
+            ((0x0a010100, 24), 0b100),
+            ((0x0b000000, 24), 0b100),
+        ]);
+        assert_eq!(
+            propagate(&matched),
+            HashMap::from([
+                ((0x0a000000, 8), 0b001),
+                ((0x0a010000, 16), 0b011),
+                ((0x0a010100, 24), 0b111),
+                ((0x0b000000, 24), 0b100),
+            ])
+        );
+    }
+
+    #[test]
+    fn rule_ports() {
+        let config = GeoConfig {
+            country_db: None,
+            asn_db: None,
+            rules: vec![
+                rule(Some("KP"), None, &[]),
+                rule(None, Some(64512), &[22, 3389]),
+                rule(None, Some(64513), &[22]),
+                rule(Some("CN"), None, &[]),
+            ],
+        };
+        let (all_ports, by_port) = port_masks(&config);
+        assert_eq!(all_ports, 0b1001);
+        assert_eq!(by_port, HashMap::from([(22, 0b0110), (3389, 0b0010)]));
+    }
+}
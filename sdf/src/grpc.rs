@@ -0,0 +1,314 @@
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::Stream;
+use log::{info, warn};
+use poem::listener::{Acceptor, AcceptorExt, BoxIo, Listener, TcpListener};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{
+        broadcast::error::RecvError,
+        mpsc::Sender,
+        oneshot::{self, Sender as OneshotSender},
+    },
+};
+use tonic::{
+    transport::{
+        server::{Connected, TcpConnectInfo},
+        Server,
+    },
+    Code, Request, Response, Status,
+};
+
+use crate::{
+    audit::ApiTokens,
+    config::TlsConfig,
+    events::DropEventInfo,
+    http::{ApiError, ControlApiCmd, HttpCmd, RuleList},
+};
+
+mod proto {
+    tonic::include_proto!("sdf.v1");
+}
+
+use proto::{
+    control_plane_server::{ControlPlane, ControlPlaneServer},
+    BlockedStatsRequest, BlockedStatsResponse, ListRulesRequest, ReloadRequest, ReloadResponse,
+    RuleKind, RuleRequest, RuleResponse, WatchDropEventsRequest,
+};
+
+impl From<ApiError> for Status {
+    fn from(e: ApiError) -> Self {
+        let code = match e {
+            ApiError::Unauthorized => Code::Unauthenticated,
+            ApiError::OnlySupportIpV4 | ApiError::InvalidDuration => Code::InvalidArgument,
+            ApiError::IpNotFound | ApiError::PortNotFound => Code::NotFound,
+            ApiError::CannotAddToMap | ApiError::CannotUpdateMap | ApiError::ReloadFailed(_) => {
+                Code::Internal
+            }
+            ApiError::MapFull => Code::ResourceExhausted,
+            ApiError::InternalQueue => Code::Unavailable,
+        };
+        Status::new(code, e.to_string())
+    }
+}
+
+impl From<RuleList> for proto::RuleList {
+    fn from(rules: RuleList) -> Self {
+        Self {
+            source_blacklist: rules.source_blacklist,
+            source_whitelist: rules.source_whitelist,
+            port_blacklist: rules.port_blacklist.into_iter().map(u32::from).collect(),
+            auto_whitelist: rules.auto_whitelist,
+            dns_whitelist: rules.dns_whitelist,
+            interfaces: rules
+                .interfaces
+                .into_iter()
+                .map(|rules| proto::InterfaceRuleList {
+                    interface: rules.interface,
+                    source_blacklist: rules.source_blacklist,
+                    source_whitelist: rules.source_whitelist,
+                    port_blacklist: rules.port_blacklist.into_iter().map(u32::from).collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<DropEventInfo> for proto::DropEvent {
+    fn from(event: DropEventInfo) -> Self {
+        Self {
+            seq: event.seq,
+            timestamp: event.timestamp,
+            source: event.source,
+            source_port: event.source_port.into(),
+            dest: event.dest,
+            dest_port: event.dest_port.into(),
+            reason: event.reason,
+            matched: event.matched.unwrap_or_default(),
+        }
+    }
+}
+
+/// gRPC front-end, sends the same commands as the HTTP api to the main loop
+pub struct GrpcControl {
+    tx: Sender<HttpCmd>,
+    tokens: ApiTokens,
+}
+
+impl GrpcControl {
+    async fn request<D, T>(
+        &self,
+        req: &Request<T>,
+        cmd: impl FnOnce(OneshotSender<Result<D, ApiError>>) -> ControlApiCmd,
+    ) -> Result<D, Status> {
+        let bearer = req
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let remote_addr = req
+            .remote_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        let caller = self
+            .tokens
+            .caller(bearer, remote_addr)
+            .ok_or(ApiError::Unauthorized)?;
+
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(HttpCmd::ControlApi(caller, cmd(tx)))
+            .await
+            .map_err(|_| ApiError::InternalQueue)?;
+        Ok(rx.await.map_err(|_| ApiError::InternalQueue)??)
+    }
+
+    async fn rule(&self, req: Request<RuleRequest>, add: bool) -> Result<String, Status> {
+        let value = req.get_ref().value.clone();
+        let kind = RuleKind::try_from(req.get_ref().kind)
+            .map_err(|_| Status::invalid_argument("unknown rule kind"))?;
+        match kind {
+            RuleKind::SourceBlacklist if add => {
+                self.request(&req, |tx| ControlApiCmd::SetBlacklistSourceRule(value, tx))
+                    .await
+            }
+            RuleKind::SourceBlacklist => {
+                self.request(&req, |tx| ControlApiCmd::DelBlacklistSourceRule(value, tx))
+                    .await
+            }
+            RuleKind::SourceWhitelist if add => {
+                self.request(&req, |tx| ControlApiCmd::SetWhitelistSourceRule(value, tx))
+                    .await
+            }
+            RuleKind::SourceWhitelist => {
+                self.request(&req, |tx| ControlApiCmd::DelWhitelistSourceRule(value, tx))
+                    .await
+            }
+            RuleKind::PortBlacklist => {
+                let port = value
+                    .parse::<u16>()
+                    .map_err(|_| Status::invalid_argument("invalid port"))?;
+                if add {
+                    self.request(&req, |tx| ControlApiCmd::SetBlacklistPortRule(port, tx))
+                        .await
+                } else {
+                    self.request(&req, |tx| ControlApiCmd::DelBlacklistPortRule(port, tx))
+                        .await
+                }
+            }
+            RuleKind::Unspecified => Err(Status::invalid_argument("rule kind is required")),
+        }
+    }
+}
+
+type DropEventStream = Pin<Box<dyn Stream<Item = Result<proto::DropEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl ControlPlane for GrpcControl {
+    type WatchDropEventsStream = DropEventStream;
+
+    async fn list_rules(
+        &self,
+        req: Request<ListRulesRequest>,
+    ) -> Result<Response<proto::RuleList>, Status> {
+        let rules = self.request(&req, ControlApiCmd::ListRules).await?;
+        Ok(Response::new(rules.into()))
+    }
+
+    async fn add_rule(&self, req: Request<RuleRequest>) -> Result<Response<RuleResponse>, Status> {
+        let result = self.rule(req, true).await?;
+        Ok(Response::new(RuleResponse { result }))
+    }
+
+    async fn remove_rule(
+        &self,
+        req: Request<RuleRequest>,
+    ) -> Result<Response<RuleResponse>, Status> {
+        let result = self.rule(req, false).await?;
+        Ok(Response::new(RuleResponse { result }))
+    }
+
+    async fn reload(
+        &self,
+        req: Request<ReloadRequest>,
+    ) -> Result<Response<ReloadResponse>, Status> {
+        let result = self.request(&req, ControlApiCmd::Reload).await?;
+        Ok(Response::new(ReloadResponse { result }))
+    }
+
+    async fn blocked_stats(
+        &self,
+        req: Request<BlockedStatsRequest>,
+    ) -> Result<Response<BlockedStatsResponse>, Status> {
+        let stats = self.request(&req, ControlApiCmd::BlockedStats).await?;
+        Ok(Response::new(BlockedStatsResponse {
+            blocked: stats
+                .into_iter()
+                .map(|(port, count)| (port.into(), count))
+                .collect(),
+        }))
+    }
+
+    async fn watch_drop_events(
+        &self,
+        req: Request<WatchDropEventsRequest>,
+    ) -> Result<Response<Self::WatchDropEventsStream>, Status> {
+        let mut rx = self
+            .request(&req, ControlApiCmd::SubscribeDropEvents)
+            .await?;
+        let stream = async_stream::stream! {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => yield Ok(proto::DropEvent::from(event)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("grpc drop event watcher lagged, skipped {} events", skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        };
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+/// Connection accepted by the poem listener, handed to tonic with its addresses
+struct GrpcIo(BoxIo, TcpConnectInfo);
+
+impl Connected for GrpcIo {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.1.clone()
+    }
+}
+
+impl AsyncRead for GrpcIo {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for GrpcIo {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// Bind the gRPC control plane and load its certificate, errors fail the startup. TLS goes
+/// through the listener of the control api, so the certificate is reloaded the same way
+pub async fn start_grpc_server(
+    tx: Sender<HttpCmd>,
+    addr: SocketAddr,
+    tls: Option<TlsConfig>,
+    tokens: ApiTokens,
+) -> anyhow::Result<impl Future<Output = Result<(), tonic::transport::Error>> + Send> {
+    let mut acceptor = match tls {
+        Some(tls) => TcpListener::bind(addr)
+            .rustls(tls.into_stream()?)
+            .into_acceptor()
+            .await?
+            .boxed(),
+        None => TcpListener::bind(addr).into_acceptor().await?.boxed(),
+    };
+    let incoming = async_stream::stream! {
+        loop {
+            match acceptor.accept().await {
+                Ok((io, local_addr, remote_addr, _)) => {
+                    let info = TcpConnectInfo {
+                        local_addr: local_addr.as_socket_addr().copied(),
+                        remote_addr: remote_addr.as_socket_addr().copied(),
+                    };
+                    yield Ok::<_, io::Error>(GrpcIo(io, info));
+                }
+                Err(e) => warn!("grpc accept error {}", e),
+            }
+        }
+    };
+
+    info!("grpc control plane listening on {}", addr);
+    Ok(Server::builder()
+        .add_service(ControlPlaneServer::new(GrpcControl { tx, tokens }))
+        .serve_with_incoming(incoming))
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use poem_openapi::Object;
+
+use crate::stats::Snapshot;
+
+/// Average rates between two snapshots
+#[derive(Object, Debug, Default)]
+pub struct Rates {
+    /// RFC 3339 time of the newest snapshot used
+    pub timestamp: String,
+    /// Seconds covered, shorter than asked while the history fills up
+    pub window_secs: f64,
+    pub passed_per_sec: f64,
+    pub dropped_per_sec: f64,
+    pub dropped_per_sec_by_reason: HashMap<String, f64>,
+    pub dropped_per_sec_by_port: HashMap<u16, f64>,
+}
+
+impl Rates {
+    fn between(older: &(Instant, Snapshot), newer: &(Instant, Snapshot)) -> Self {
+        let window = newer.0.duration_since(older.0).as_secs_f64();
+        // counters can go back after a reset, count it as no traffic
+        let rate = |new: u64, old: u64| new.saturating_sub(old) as f64 / window;
+        let (old, new) = (&older.1, &newer.1);
+
+        Self {
+            timestamp: new.timestamp.clone(),
+            window_secs: window,
+            passed_per_sec: rate(new.packets.passed, old.packets.passed),
+            dropped_per_sec: rate(new.packets.dropped, old.packets.dropped),
+            dropped_per_sec_by_reason: new
+                .packets
+                .dropped_by_reason
+                .iter()
+                .map(|(reason, count)| {
+                    let before = old.packets.dropped_by_reason.get(reason).copied();
+                    (reason.clone(), rate(*count, before.unwrap_or_default()))
+                })
+                .collect(),
+            dropped_per_sec_by_port: new
+                .dropped_by_port
+                .iter()
+                .map(|(port, count)| {
+                    let before = old.dropped_by_port.get(port).copied();
+                    (*port, rate(*count, before.unwrap_or_default()))
+                })
+                .filter(|(_, rate)| *rate > 0.0)
+                .collect(),
+        }
+    }
+}
+
+/// Ring buffer of the counter snapshots taken by the main loop
+pub struct StatsHistory {
+    retention: Duration,
+    samples: VecDeque<(Instant, Snapshot)>,
+}
+
+impl StatsHistory {
+    pub fn new(retention: Duration) -> Self {
+        Self {
+            retention,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        self.push_at(Instant::now(), snapshot);
+    }
+
+    fn push_at(&mut self, now: Instant, snapshot: Snapshot) {
+        while let Some((time, _)) = self.samples.front() {
+            if now.duration_since(*time) <= self.retention {
+                break;
+            }
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, snapshot));
+    }
+
+    /// Forget every snapshot, used when the counters are reset
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Snapshots of the last `window`, oldest first
+    fn window(&self, window: Duration) -> impl Iterator<Item = &(Instant, Snapshot)> {
+        let newest = self.samples.back().map(|(time, _)| *time);
+        self.samples.iter().filter(move |(time, _)| match newest {
+            Some(newest) => newest.duration_since(*time) <= window,
+            None => false,
+        })
+    }
+
+    /// Average rates over the last `window`, all zero until two snapshots were taken
+    pub fn rates(&self, window: Duration) -> Rates {
+        match (self.window(window).next(), self.samples.back()) {
+            (Some(older), Some(newer)) if older.0 < newer.0 => Rates::between(older, newer),
+            _ => Rates::default(),
+        }
+    }
+
+    /// Rates between each pair of consecutive snapshots of the last `window`, oldest first
+    pub fn history(&self, window: Duration) -> Vec<Rates> {
+        let samples: Vec<_> = self.window(window).collect();
+        samples
+            .windows(2)
+            .map(|pair| Rates::between(pair[0], pair[1]))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::StatsHistory;
+    use crate::stats::{PacketStats, Snapshot};
+
+    fn snapshot(passed: u64, dropped_53: u64) -> Snapshot {
+        Snapshot {
+            timestamp: passed.to_string(),
+            packets: PacketStats {
+                passed,
+                dropped: dropped_53,
+                dropped_by_reason: [("port_blacklist".to_string(), dropped_53)].into(),
+                ..Default::default()
+            },
+            dropped_by_port: [(53, dropped_53), (123, 7)].into(),
+        }
+    }
+
+    #[test]
+    fn window_rates() {
+        let start = Instant::now();
+        let secs = |s| start + Duration::from_secs(s);
+        let mut history = StatsHistory::new(Duration::from_secs(3600));
+        assert_eq!(history.rates(Duration::from_secs(60)).window_secs, 0.0);
+
+        history.push_at(secs(0), snapshot(0, 0));
+        history.push_at(secs(5), snapshot(50, 10));
+        history.push_at(secs(10), snapshot(150, 10));
+
+        let rates = history.rates(Duration::from_secs(5));
+        assert_eq!(rates.timestamp, "150");
+        assert_eq!(rates.window_secs, 5.0);
+        assert_eq!(rates.passed_per_sec, 20.0);
+        assert_eq!(rates.dropped_per_sec, 0.0);
+        assert_eq!(rates.dropped_per_sec_by_reason["port_blacklist"], 0.0);
+        // ports without new drops are left out
+        assert!(rates.dropped_per_sec_by_port.is_empty());
+
+        let rates = history.rates(Duration::from_secs(3600));
+        assert_eq!(rates.window_secs, 10.0);
+        assert_eq!(rates.passed_per_sec, 15.0);
+        assert_eq!(rates.dropped_per_sec_by_port[&53], 1.0);
+        assert_eq!(rates.dropped_per_sec_by_port.get(&123), None);
+
+        let history = history.history(Duration::from_secs(3600));
+        let passed: Vec<_> = history.iter().map(|r| r.passed_per_sec).collect();
+        assert_eq!(passed, [10.0, 20.0]);
+    }
+
+    #[test]
+    fn counter_reset() {
+        let start = Instant::now();
+        let mut history = StatsHistory::new(Duration::from_secs(3600));
+        history.push_at(start, snapshot(100, 10));
+        history.push_at(start + Duration::from_secs(2), snapshot(20, 0));
+        let rates = history.rates(Duration::from_secs(60));
+        assert_eq!(rates.passed_per_sec, 0.0);
+        assert_eq!(rates.dropped_per_sec, 0.0);
+    }
+
+    #[test]
+    fn retention() {
+        let start = Instant::now();
+        let secs = |s| start + Duration::from_secs(s);
+        let mut history = StatsHistory::new(Duration::from_secs(10));
+        history.push_at(secs(0), snapshot(0, 0));
+        history.push_at(secs(5), snapshot(50, 0));
+        history.push_at(secs(10), snapshot(100, 0));
+        assert_eq!(history.samples.len(), 3);
+        history.push_at(secs(16), snapshot(160, 0));
+        // the snapshots older than the retention are dropped, the rest is kept
+        let times: Vec<_> = history.samples.iter().map(|(time, _)| *time).collect();
+        assert_eq!(times, [secs(10), secs(16)]);
+        assert_eq!(history.rates(Duration::from_secs(3600)).window_secs, 6.0);
+
+        history.clear();
+        assert_eq!(history.history(Duration::from_secs(3600)).len(), 0);
+    }
+}
@@ -1,14 +1,37 @@
-use poem::{listener::TcpListener, middleware::Cors, EndpointExt, Result, Route, Server};
+use poem::{
+    endpoint::EmbeddedFilesEndpoint,
+    http::{header, StatusCode},
+    listener::{AcceptorExt, Listener, TcpListener},
+    middleware::Cors,
+    web::Addr,
+    EndpointExt, Request, Result, Route, Server,
+};
 use poem_openapi::{
+    payload::Json,
     types::{ParseFromJSON, ToJSON, Type},
-    Object, OpenApiService,
+    ApiResponse, Object, OpenApiService,
 };
+use std::future::Future;
+
 use tokio::sync::mpsc::Sender;
 
 mod control_api;
+mod dashboard;
+mod error;
+mod tls;
+mod unix;
+mod v1;
 
+use crate::{
+    audit::{ApiTokens, Caller},
+    config::TlsConfig,
+};
 use control_api::ControlApi;
-pub use control_api::ControlApiCmd;
+pub use control_api::{ControlApiCmd, RuleList};
+use dashboard::DashboardAssets;
+pub use error::ApiError;
+pub use unix::UnixSocketConfig;
+use v1::ControlApiV1;
 
 #[derive(Object, Debug)]
 pub struct ApiResult<D: ParseFromJSON + ToJSON + Type + Send + Sync> {
@@ -35,22 +58,106 @@ impl<T: ParseFromJSON + ToJSON + Type + Send + Sync> ApiResult<T> {
     }
 }
 
+impl<T: ParseFromJSON + ToJSON + Type + Send + Sync> From<Result<T, ApiError>> for ApiResult<T> {
+    fn from(value: Result<T, ApiError>) -> Self {
+        match value {
+            Ok(data) => Self::success(data),
+            Err(e) => Self::error(&e.to_string()),
+        }
+    }
+}
+
+/// `ApiResult` envelope with a status code matching the error, used by the `/v1` api
+#[derive(ApiResponse)]
+pub enum RestResponse<T: ParseFromJSON + ToJSON + Type + Send + Sync> {
+    #[oai(status = 200)]
+    Ok(Json<ApiResult<T>>),
+    #[oai(status = 400)]
+    BadRequest(Json<ApiResult<T>>),
+    #[oai(status = 401)]
+    Unauthorized(Json<ApiResult<T>>),
+    #[oai(status = 404)]
+    NotFound(Json<ApiResult<T>>),
+    #[oai(status = 500)]
+    InternalError(Json<ApiResult<T>>),
+    #[oai(status = 503)]
+    Unavailable(Json<ApiResult<T>>),
+    /// The map the rule goes to is full
+    #[oai(status = 507)]
+    InsufficientStorage(Json<ApiResult<T>>),
+}
+
+impl<T: ParseFromJSON + ToJSON + Type + Send + Sync> From<Result<T, ApiError>> for RestResponse<T> {
+    fn from(value: Result<T, ApiError>) -> Self {
+        let status = match &value {
+            Ok(_) => StatusCode::OK,
+            Err(e) => e.status(),
+        };
+        let body = Json(ApiResult::from(value));
+        match status {
+            StatusCode::OK => Self::Ok(body),
+            StatusCode::BAD_REQUEST => Self::BadRequest(body),
+            StatusCode::UNAUTHORIZED => Self::Unauthorized(body),
+            StatusCode::NOT_FOUND => Self::NotFound(body),
+            StatusCode::SERVICE_UNAVAILABLE => Self::Unavailable(body),
+            StatusCode::INSUFFICIENT_STORAGE => Self::InsufficientStorage(body),
+            _ => Self::InternalError(body),
+        }
+    }
+}
+
 pub enum HttpCmd {
-    ControlApi(ControlApiCmd),
+    ControlApi(Caller, ControlApiCmd),
 }
 
 #[derive(Clone)]
 pub struct HttpContext {
     tx: Sender<HttpCmd>,
+    tokens: ApiTokens,
+}
+
+impl HttpContext {
+    /// Resolve who is calling, unix socket peers are always trusted.
+    /// Returns None when tokens are configured and the request doesn't carry a valid one.
+    fn caller(&self, req: &Request) -> Option<Caller> {
+        let remote_addr = req.remote_addr().to_string();
+        // access to the unix socket is already granted by its file permissions
+        if let Addr::Unix(_) = &req.remote_addr().0 {
+            return Some(Caller {
+                identity: "local".to_string(),
+                remote_addr,
+            });
+        }
+
+        let bearer = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        self.tokens.caller(bearer, remote_addr)
+    }
 }
 
+pub struct HttpServerConfig {
+    /// TCP address of the control API, None to only serve on the unix socket
+    pub tcp: Option<String>,
+    pub tls: Option<TlsConfig>,
+    pub unix_socket: Option<UnixSocketConfig>,
+    pub api_tokens: ApiTokens,
+}
+
+/// Bind the listeners and load the TLS material, so a bad address, certificate or socket
+/// path fails the startup. The returned future serves the control API
 pub async fn start_http_server(
     tx: Sender<HttpCmd>,
-    addr: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let ctx = HttpContext { tx };
+    config: HttpServerConfig,
+) -> anyhow::Result<impl Future<Output = std::io::Result<()>> + Send> {
+    let ctx = HttpContext {
+        tx,
+        tokens: config.api_tokens,
+    };
     let control_api_service = OpenApiService::new(
-        ControlApi,
+        (ControlApi, ControlApiV1),
         "Software Defined Firewall API",
         env!("CARGO_PKG_VERSION"),
     )
@@ -60,9 +167,34 @@ pub async fn start_http_server(
     let route = Route::new()
         .nest("/", control_api_service)
         .nest("/ui", ui)
+        .nest(
+            "/dashboard",
+            EmbeddedFilesEndpoint::<DashboardAssets>::new(),
+        )
         .at("/spec", poem::endpoint::make_sync(move |_| spec.clone()))
         .with(Cors::new())
         .data(ctx);
-    Server::new(TcpListener::bind(addr)).run(route).await?;
-    Ok(())
+
+    let tcp = match (config.tcp, config.tls) {
+        (Some(addr), Some(tls)) => Some(
+            TcpListener::bind(addr)
+                .rustls(tls.into_stream()?)
+                .into_acceptor()
+                .await?
+                .boxed(),
+        ),
+        (Some(addr), None) => Some(TcpListener::bind(addr).into_acceptor().await?.boxed()),
+        (None, _) => None,
+    };
+    let unix = match &config.unix_socket {
+        Some(unix_socket) => Some(unix_socket.bind()?),
+        None => None,
+    };
+    let acceptor = match (tcp, unix) {
+        (Some(tcp), Some(unix)) => tcp.combine(unix).boxed(),
+        (Some(tcp), None) => tcp,
+        (None, Some(unix)) => unix.boxed(),
+        (None, None) => anyhow::bail!("control api needs a tcp address or a unix socket"),
+    };
+    Ok(Server::new_with_acceptor(acceptor).run(route))
 }
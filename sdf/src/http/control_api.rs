@@ -1,182 +1,233 @@
-use std::{collections::HashMap, net::Ipv4Addr};
+use std::{collections::HashMap, time::Duration};
+
+use poem::{web::Data, Request, Result};
+use poem_openapi::{
+    param::{Path, Query},
+    payload::Json,
+    Object, OpenApi,
+};
+use tokio::sync::{
+    broadcast,
+    oneshot::{self, Sender},
+};
+
+use super::{v1::DEFAULT_AUDIT_LIMIT, ApiError, ApiResult, HttpCmd, HttpContext};
+use crate::{
+    attach::InterfaceStatus,
+    audit::AuditRecord,
+    autoban::AutoBanInfo,
+    dns::DnsRuleStatus,
+    events::DropEventInfo,
+    feed::FeedStatus,
+    history::Rates,
+    iface::InterfaceRuleList,
+    stats::{InterfaceStats, MapUsage, PacketStats, Snapshot, SourceStats},
+    trap::TrapList,
+};
+
+/// Legacy unversioned api, always answers 200 and reports errors in the envelope only
+pub struct ControlApi;
 
-use poem::{web::Data, Result};
-use poem_openapi::{param::Path, payload::Json, OpenApi};
-use tokio::sync::oneshot::{self, Sender};
+pub type CmdResult<T> = Sender<Result<T, ApiError>>;
+
+/// Rules currently loaded in the eBPF maps
+#[derive(Object, Debug, Default)]
+pub struct RuleList {
+    pub source_blacklist: Vec<String>,
+    pub source_whitelist: Vec<String>,
+    pub port_blacklist: Vec<u16>,
+    /// Destinations whitelisted by the egress program, the oldest are evicted when full
+    pub auto_whitelist: Vec<String>,
+    /// Addresses of source_whitelist resolved from dns names, they aren't part of the config
+    pub dns_whitelist: Vec<String>,
+    /// Rules of the `interfaces` section, for the attached interfaces that have some
+    pub interfaces: Vec<InterfaceRuleList>,
+}
 
-use super::{ApiResult, HttpCmd, HttpContext};
+pub enum ControlApiCmd {
+    SetBlacklistSourceRule(String, CmdResult<String>),
+    DelBlacklistSourceRule(String, CmdResult<String>),
+    SetWhitelistSourceRule(String, CmdResult<String>),
+    DelWhitelistSourceRule(String, CmdResult<String>),
+    SetBlacklistPortRule(u16, CmdResult<String>),
+    DelBlacklistPortRule(u16, CmdResult<String>),
+    Reload(CmdResult<String>),
+    ListRules(CmdResult<RuleList>),
+    BlockedStats(CmdResult<HashMap<u16, u64>>),
+    PacketStats(CmdResult<PacketStats>),
+    InterfaceStats(CmdResult<Vec<InterfaceStats>>),
+    Interfaces(CmdResult<Vec<InterfaceStatus>>),
+    /// Sources with the most dropped packets, at most the first field
+    TopSources(usize, CmdResult<Vec<SourceStats>>),
+    /// Average rates over the given window
+    StatsRates(Duration, CmdResult<Rates>),
+    /// Rates between consecutive snapshots over the given window
+    StatsHistory(Duration, CmdResult<Vec<Rates>>),
+    StatsSnapshot(CmdResult<Snapshot>),
+    ResetStats(CmdResult<String>),
+    ListAutoBans(CmdResult<Vec<AutoBanInfo>>),
+    LiftAutoBan(String, CmdResult<String>),
+    ListTraps(CmdResult<TrapList>),
+    LiftTrapBan(String, CmdResult<String>),
+    ListFeeds(CmdResult<Vec<FeedStatus>>),
+    MapUsage(CmdResult<Vec<MapUsage>>),
+    ListDnsRules(CmdResult<Vec<DnsRuleStatus>>),
+    AuditLog(usize, CmdResult<Vec<AuditRecord>>),
+    /// Recent drop events with a sequence number above the first field, at most the second field
+    DropEvents(u64, usize, CmdResult<Vec<DropEventInfo>>),
+    SubscribeDropEvents(CmdResult<broadcast::Receiver<DropEventInfo>>),
+}
 
-pub struct ControlApi;
+impl HttpContext {
+    /// Send a command to the main loop on behalf of the caller and wait for its answer
+    pub(super) async fn request<D>(
+        &self,
+        req: &Request,
+        cmd: impl FnOnce(CmdResult<D>) -> ControlApiCmd,
+    ) -> Result<D, ApiError> {
+        let caller = self.caller(req).ok_or(ApiError::Unauthorized)?;
 
-pub enum ControlApiCmd {
-    SetBlacklistSourceRule(String, Sender<ApiResult<String>>),
-    DelBlacklistSourceRule(String, Sender<ApiResult<String>>),
-    SetWhitelistSourceRule(String, Sender<ApiResult<String>>),
-    DelWhitelistSourceRule(String, Sender<ApiResult<String>>),
-    SetBlacklistPortRule(u16, Sender<ApiResult<String>>),
-    DelBlacklistPortRule(u16, Sender<ApiResult<String>>),
-    Reload(Sender<ApiResult<String>>),
-    BlockedStats(Sender<ApiResult<HashMap<u16, u64>>>),
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(HttpCmd::ControlApi(caller, cmd(tx)))
+            .await
+            .map_err(|_| ApiError::InternalQueue)?;
+        rx.await.map_err(|_| ApiError::InternalQueue)?
+    }
 }
 
 #[OpenApi]
 impl ControlApi {
     /// Set a source blacklist rule
-    #[oai(path = "/rules/blacklist/source/:ip", method = "post")]
+    #[oai(path = "/rules/blacklist/source/:ip", method = "post", deprecated)]
     async fn set_blacklist_source_rule(
         &self,
         ctx: Data<&HttpContext>,
+        req: &Request,
         ip: Path<String>,
     ) -> Result<Json<ApiResult<String>>> {
-        let (tx, rx) = oneshot::channel();
-        ctx.tx
-            .send(HttpCmd::ControlApi(ControlApiCmd::SetBlacklistSourceRule(
-                ip.0, tx,
-            )))
-            .await
-            .expect("Should work");
-
-        match rx.await {
-            Ok(res) => Ok(Json(res)),
-            Err(_) => Ok(Json(ApiResult::error("INTERNAL_QUEUE_ERROR"))),
-        }
+        Ok(Json(
+            ctx.request(req, |tx| ControlApiCmd::SetBlacklistSourceRule(ip.0, tx))
+                .await
+                .into(),
+        ))
     }
 
     /// Del a source blacklist rule
-    #[oai(path = "/rules/blacklist/source/:ip", method = "post")]
+    #[oai(path = "/rules/blacklist/source/:ip", method = "delete", deprecated)]
     async fn del_blacklist_source_rule(
         &self,
         ctx: Data<&HttpContext>,
+        req: &Request,
         ip: Path<String>,
     ) -> Result<Json<ApiResult<String>>> {
-        let (tx, rx) = oneshot::channel();
-        ctx.tx
-            .send(HttpCmd::ControlApi(ControlApiCmd::DelBlacklistSourceRule(
-                ip.0, tx,
-            )))
-            .await
-            .expect("Should work");
-
-        match rx.await {
-            Ok(res) => Ok(Json(res)),
-            Err(_) => Ok(Json(ApiResult::error("INTERNAL_QUEUE_ERROR"))),
-        }
+        Ok(Json(
+            ctx.request(req, |tx| ControlApiCmd::DelBlacklistSourceRule(ip.0, tx))
+                .await
+                .into(),
+        ))
     }
 
     /// Set a source blacklist rule
-    #[oai(path = "/rules/whitelist/source/:ip", method = "post")]
+    #[oai(path = "/rules/whitelist/source/:ip", method = "post", deprecated)]
     async fn set_whitelist_source_rule(
         &self,
         ctx: Data<&HttpContext>,
+        req: &Request,
         ip: Path<String>,
     ) -> Result<Json<ApiResult<String>>> {
-        let (tx, rx) = oneshot::channel();
-        ctx.tx
-            .send(HttpCmd::ControlApi(ControlApiCmd::SetWhitelistSourceRule(
-                ip.0, tx,
-            )))
-            .await
-            .expect("Should work");
-
-        match rx.await {
-            Ok(res) => Ok(Json(res)),
-            Err(_) => Ok(Json(ApiResult::error("INTERNAL_QUEUE_ERROR"))),
-        }
+        Ok(Json(
+            ctx.request(req, |tx| ControlApiCmd::SetWhitelistSourceRule(ip.0, tx))
+                .await
+                .into(),
+        ))
     }
 
-    /// Del a source blacklist rule
-    #[oai(path = "/rules/whitelist/source/:ip", method = "post")]
+    /// Del a source whitelist rule
+    #[oai(path = "/rules/whitelist/source/:ip", method = "delete", deprecated)]
     async fn del_whitelist_source_rule(
         &self,
         ctx: Data<&HttpContext>,
+        req: &Request,
         ip: Path<String>,
     ) -> Result<Json<ApiResult<String>>> {
-        let (tx, rx) = oneshot::channel();
-        ctx.tx
-            .send(HttpCmd::ControlApi(ControlApiCmd::DelWhitelistSourceRule(
-                ip.0, tx,
-            )))
-            .await
-            .expect("Should work");
-
-        match rx.await {
-            Ok(res) => Ok(Json(res)),
-            Err(_) => Ok(Json(ApiResult::error("INTERNAL_QUEUE_ERROR"))),
-        }
+        Ok(Json(
+            ctx.request(req, |tx| ControlApiCmd::DelWhitelistSourceRule(ip.0, tx))
+                .await
+                .into(),
+        ))
     }
 
     /// Set a port blacklist rule
-    #[oai(path = "/rules/blacklist/port/:port", method = "post")]
+    #[oai(path = "/rules/blacklist/port/:port", method = "post", deprecated)]
     async fn set_port_rule(
         &self,
         ctx: Data<&HttpContext>,
+        req: &Request,
         port: Path<u16>,
     ) -> Result<Json<ApiResult<String>>> {
-        let (tx, rx) = oneshot::channel();
-        ctx.tx
-            .send(HttpCmd::ControlApi(ControlApiCmd::SetBlacklistPortRule(
-                port.0, tx,
-            )))
-            .await
-            .expect("Should work");
-
-        match rx.await {
-            Ok(res) => Ok(Json(res)),
-            Err(_) => Ok(Json(ApiResult::error("INTERNAL_QUEUE_ERROR"))),
-        }
+        Ok(Json(
+            ctx.request(req, |tx| ControlApiCmd::SetBlacklistPortRule(port.0, tx))
+                .await
+                .into(),
+        ))
     }
 
     /// Del a port blacklist rule
-    #[oai(path = "/rules/blacklist/port/:port", method = "delete")]
+    #[oai(path = "/rules/blacklist/port/:port", method = "delete", deprecated)]
     async fn del_port_rule(
         &self,
         ctx: Data<&HttpContext>,
+        req: &Request,
         port: Path<u16>,
     ) -> Result<Json<ApiResult<String>>> {
-        let (tx, rx) = oneshot::channel();
-        ctx.tx
-            .send(HttpCmd::ControlApi(ControlApiCmd::DelBlacklistPortRule(
-                port.0, tx,
-            )))
-            .await
-            .expect("Should work");
-
-        match rx.await {
-            Ok(res) => Ok(Json(res)),
-            Err(_) => Ok(Json(ApiResult::error("INTERNAL_QUEUE_ERROR"))),
-        }
+        Ok(Json(
+            ctx.request(req, |tx| ControlApiCmd::DelBlacklistPortRule(port.0, tx))
+                .await
+                // PORT_NOT_FOUND came with /v1, clients of this route expect IP_NOT_FOUND
+                .map_err(|e| match e {
+                    ApiError::PortNotFound => ApiError::IpNotFound,
+                    e => e,
+                })
+                .into(),
+        ))
     }
 
     /// Set a source blacklist rule
-    #[oai(path = "/rules/reload", method = "get")]
-    async fn reload_rule(&self, ctx: Data<&HttpContext>) -> Result<Json<ApiResult<String>>> {
-        let (tx, rx) = oneshot::channel();
-        ctx.tx
-            .send(HttpCmd::ControlApi(ControlApiCmd::Reload(tx)))
-            .await
-            .expect("Should work");
-
-        match rx.await {
-            Ok(res) => Ok(Json(res)),
-            Err(_) => Ok(Json(ApiResult::error("INTERNAL_QUEUE_ERROR"))),
-        }
+    #[oai(path = "/rules/reload", method = "get", deprecated)]
+    async fn reload_rule(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+    ) -> Result<Json<ApiResult<String>>> {
+        Ok(Json(ctx.request(req, ControlApiCmd::Reload).await.into()))
     }
 
     /// Set a source blacklist rule
-    #[oai(path = "/stats/blocked", method = "get")]
+    #[oai(path = "/stats/blocked", method = "get", deprecated)]
     async fn stats_blocked(
         &self,
         ctx: Data<&HttpContext>,
+        req: &Request,
     ) -> Result<Json<ApiResult<HashMap<u16, u64>>>> {
-        let (tx, rx) = oneshot::channel();
-        ctx.tx
-            .send(HttpCmd::ControlApi(ControlApiCmd::BlockedStats(tx)))
-            .await
-            .expect("Should work");
+        Ok(Json(
+            ctx.request(req, ControlApiCmd::BlockedStats).await.into(),
+        ))
+    }
 
-        match rx.await {
-            Ok(res) => Ok(Json(res)),
-            Err(_) => Ok(Json(ApiResult::error("INTERNAL_QUEUE_ERROR"))),
-        }
+    /// Recent rule changes, newest first, replaced by `/v1/audit`
+    #[oai(path = "/audit", method = "get", deprecated)]
+    async fn audit_log(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+        limit: Query<Option<usize>>,
+    ) -> Result<Json<ApiResult<Vec<AuditRecord>>>> {
+        let limit = limit.0.unwrap_or(DEFAULT_AUDIT_LIMIT);
+        Ok(Json(
+            ctx.request(req, |tx| ControlApiCmd::AuditLog(limit, tx))
+                .await
+                .into(),
+        ))
     }
 }
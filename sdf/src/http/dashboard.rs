@@ -0,0 +1,6 @@
+use rust_embed::RustEmbed;
+
+/// Static dashboard built on top of the `/v1` api, embedded in the binary
+#[derive(RustEmbed)]
+#[folder = "dashboard"]
+pub struct DashboardAssets;
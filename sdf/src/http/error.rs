@@ -0,0 +1,63 @@
+use std::fmt;
+
+use poem::http::StatusCode;
+
+/// Errors returned by control API commands.
+///
+/// The `code` is what older clients see in the `error` field of `ApiResult`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError {
+    Unauthorized,
+    OnlySupportIpV4,
+    IpNotFound,
+    PortNotFound,
+    CannotAddToMap,
+    CannotUpdateMap,
+    /// The map holding the rule has no room left
+    MapFull,
+    InvalidDuration,
+    ReloadFailed(String),
+    InternalQueue,
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Unauthorized => "UNAUTHORIZED",
+            ApiError::OnlySupportIpV4 => "ONLY_SUPPORT_IP_V4",
+            ApiError::IpNotFound => "IP_NOT_FOUND",
+            ApiError::PortNotFound => "PORT_NOT_FOUND",
+            ApiError::CannotAddToMap => "CANNOT_ADD_TO_MAP",
+            ApiError::CannotUpdateMap => "CANNOT_UPDATE_MAP",
+            ApiError::MapFull => "MAP_FULL",
+            ApiError::InvalidDuration => "INVALID_DURATION",
+            ApiError::ReloadFailed(_) => "RELOAD_FAILED",
+            ApiError::InternalQueue => "INTERNAL_QUEUE_ERROR",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::OnlySupportIpV4 | ApiError::InvalidDuration => StatusCode::BAD_REQUEST,
+            ApiError::IpNotFound | ApiError::PortNotFound => StatusCode::NOT_FOUND,
+            ApiError::CannotAddToMap | ApiError::CannotUpdateMap | ApiError::ReloadFailed(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            ApiError::MapFull => StatusCode::INSUFFICIENT_STORAGE,
+            ApiError::InternalQueue => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // reload used to report the underlying config error as is
+            ApiError::ReloadFailed(reason) => f.write_str(reason),
+            _ => f.write_str(self.code()),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
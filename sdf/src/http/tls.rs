@@ -0,0 +1,76 @@
+use std::{io, path::Path, time::SystemTime};
+
+use futures_util::Stream;
+use log::{info, warn};
+use poem::listener::{RustlsCertificate, RustlsConfig};
+use tokio::{
+    select,
+    signal::unix::{signal, SignalKind},
+};
+
+use crate::config::TlsConfig;
+
+/// How often the certificate files are checked for changes
+const RELOAD_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl TlsConfig {
+    fn load(&self) -> io::Result<RustlsConfig> {
+        let cert = std::fs::read(&self.cert)?;
+        let key = std::fs::read(&self.key)?;
+        let config = RustlsConfig::new().fallback(RustlsCertificate::new().cert(cert).key(key));
+        match &self.client_ca {
+            Some(ca) => Ok(config.client_auth_required(std::fs::read(ca)?)),
+            None => Ok(config),
+        }
+    }
+
+    fn last_modified(&self) -> Vec<Option<SystemTime>> {
+        let mut files = vec![modified(&self.cert), modified(&self.key)];
+        if let Some(ca) = &self.client_ca {
+            files.push(modified(ca));
+        }
+        files
+    }
+
+    /// Builds a stream of rustls configs for the listener.
+    ///
+    /// The first item is loaded immediately so that a bad certificate fails the startup,
+    /// then the files are loaded again whenever they change on disk or the process receives SIGHUP.
+    /// A reload which fails keeps the previous certificate in use.
+    pub fn into_stream(self) -> io::Result<impl Stream<Item = RustlsConfig> + Send + 'static> {
+        let initial = self.load()?;
+        let mut hangup = signal(SignalKind::hangup())?;
+        Ok(async_stream::stream! {
+            let mut last_modified = self.last_modified();
+            yield initial;
+
+            let mut interval = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+            loop {
+                select! {
+                    _ = interval.tick() => {
+                        let current = self.last_modified();
+                        if current == last_modified {
+                            continue;
+                        }
+                        last_modified = current;
+                    },
+                    _ = hangup.recv() => {},
+                }
+
+                match self.load() {
+                    Ok(config) => {
+                        info!("reloaded control api tls certificate {:?}", self.cert);
+                        yield config;
+                    }
+                    Err(e) => {
+                        warn!("reload control api tls certificate error {}, keep using previous one", e);
+                    }
+                }
+            }
+        })
+    }
+}
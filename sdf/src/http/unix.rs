@@ -0,0 +1,67 @@
+use std::{
+    ffi::OsString,
+    fs::Permissions,
+    io,
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::UnixListener,
+    },
+    path::{Path, PathBuf},
+};
+
+use poem::listener::UnixAcceptor;
+
+/// Local control socket, access is granted by the socket file permissions
+#[derive(Debug, Clone)]
+pub struct UnixSocketConfig {
+    pub path: PathBuf,
+    /// File mode of the socket, like 0o660
+    pub mode: u32,
+    /// Group owning the socket, to let a group of local users in
+    pub group: Option<u32>,
+}
+
+impl UnixSocketConfig {
+    pub fn bind(&self) -> io::Result<UnixAcceptor> {
+        // a socket file left by a previous run would make bind fail, anything else is kept
+        match std::fs::symlink_metadata(&self.path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(&self.path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and isn't a socket", self.path.display()),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        // bound in a directory only the owner can enter, the socket is moved into place once
+        // the configured mode and group are set
+        let dir = self.private_dir();
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        let listener = self.bind_in(&dir);
+        let _ = std::fs::remove_dir_all(&dir);
+        let listener = listener?;
+        listener.set_nonblocking(true)?;
+        UnixAcceptor::from_std(listener)
+    }
+
+    fn private_dir(&self) -> PathBuf {
+        let mut name = OsString::from(".");
+        name.push(self.path.file_name().unwrap_or_default());
+        name.push(format!(".{}", std::process::id()));
+        self.path.with_file_name(name)
+    }
+
+    fn bind_in(&self, dir: &Path) -> io::Result<UnixListener> {
+        let bound = dir.join("socket");
+        let listener = UnixListener::bind(&bound)?;
+        std::fs::set_permissions(&bound, Permissions::from_mode(self.mode))?;
+        if let Some(gid) = self.group {
+            std::os::unix::fs::chown(&bound, None, Some(gid))?;
+        }
+        std::fs::rename(&bound, &self.path)?;
+        Ok(listener)
+    }
+}
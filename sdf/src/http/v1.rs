@@ -0,0 +1,342 @@
+use std::{collections::HashMap, time::Duration};
+
+use poem::{web::Data, Request};
+use poem_openapi::{
+    param::{Path, Query},
+    OpenApi,
+};
+
+use super::{control_api::ControlApiCmd, ApiError, HttpContext, RestResponse, RuleList};
+use crate::{
+    attach::InterfaceStatus,
+    audit::AuditRecord,
+    autoban::AutoBanInfo,
+    dns::DnsRuleStatus,
+    events::DropEventInfo,
+    feed::FeedStatus,
+    history::Rates,
+    stats::{InterfaceStats, MapUsage, PacketStats, Snapshot, SourceStats},
+    trap::TrapList,
+};
+
+/// Number of audit records returned when no limit is given
+pub(super) const DEFAULT_AUDIT_LIMIT: usize = 100;
+/// Number of drop events returned when no limit is given
+const DEFAULT_EVENTS_LIMIT: usize = 100;
+/// Number of sources returned when no limit is given
+const DEFAULT_SOURCES_LIMIT: usize = 20;
+/// Window of the rates when none is given
+const DEFAULT_RATES_WINDOW: Duration = Duration::from_secs(60);
+/// Window of the history when none is given
+const DEFAULT_HISTORY_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Parse a window like `1m`, `5m` or `1h`
+fn parse_window(window: Option<String>, default: Duration) -> Result<Duration, ApiError> {
+    match window {
+        Some(window) => humantime::parse_duration(&window).map_err(|_| ApiError::InvalidDuration),
+        None => Ok(default),
+    }
+}
+
+/// Versioned api, rules are resources added with PUT and removed with DELETE
+pub struct ControlApiV1;
+
+#[OpenApi(prefix_path = "/v1")]
+impl ControlApiV1 {
+    /// List the rules currently enforced
+    #[oai(path = "/rules", method = "get")]
+    async fn list_rules(&self, ctx: Data<&HttpContext>, req: &Request) -> RestResponse<RuleList> {
+        ctx.request(req, ControlApiCmd::ListRules).await.into()
+    }
+
+    /// Add a source blacklist rule
+    #[oai(path = "/rules/blacklist/source/:ip", method = "put")]
+    async fn put_blacklist_source_rule(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+        ip: Path<String>,
+    ) -> RestResponse<String> {
+        ctx.request(req, |tx| ControlApiCmd::SetBlacklistSourceRule(ip.0, tx))
+            .await
+            .into()
+    }
+
+    /// Remove a source blacklist rule
+    #[oai(path = "/rules/blacklist/source/:ip", method = "delete")]
+    async fn delete_blacklist_source_rule(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+        ip: Path<String>,
+    ) -> RestResponse<String> {
+        ctx.request(req, |tx| ControlApiCmd::DelBlacklistSourceRule(ip.0, tx))
+            .await
+            .into()
+    }
+
+    /// Add a source whitelist rule
+    #[oai(path = "/rules/whitelist/source/:ip", method = "put")]
+    async fn put_whitelist_source_rule(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+        ip: Path<String>,
+    ) -> RestResponse<String> {
+        ctx.request(req, |tx| ControlApiCmd::SetWhitelistSourceRule(ip.0, tx))
+            .await
+            .into()
+    }
+
+    /// Remove a source whitelist rule
+    #[oai(path = "/rules/whitelist/source/:ip", method = "delete")]
+    async fn delete_whitelist_source_rule(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+        ip: Path<String>,
+    ) -> RestResponse<String> {
+        ctx.request(req, |tx| ControlApiCmd::DelWhitelistSourceRule(ip.0, tx))
+            .await
+            .into()
+    }
+
+    /// Add a port blacklist rule
+    #[oai(path = "/rules/blacklist/port/:port", method = "put")]
+    async fn put_port_rule(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+        port: Path<u16>,
+    ) -> RestResponse<String> {
+        ctx.request(req, |tx| ControlApiCmd::SetBlacklistPortRule(port.0, tx))
+            .await
+            .into()
+    }
+
+    /// Remove a port blacklist rule
+    #[oai(path = "/rules/blacklist/port/:port", method = "delete")]
+    async fn delete_port_rule(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+        port: Path<u16>,
+    ) -> RestResponse<String> {
+        ctx.request(req, |tx| ControlApiCmd::DelBlacklistPortRule(port.0, tx))
+            .await
+            .into()
+    }
+
+    /// Reload rules from the config file
+    #[oai(path = "/rules/reload", method = "post")]
+    async fn reload_rules(&self, ctx: Data<&HttpContext>, req: &Request) -> RestResponse<String> {
+        ctx.request(req, ControlApiCmd::Reload).await.into()
+    }
+
+    /// Dropped packets per port
+    #[oai(path = "/stats/blocked", method = "get")]
+    async fn stats_blocked(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+    ) -> RestResponse<HashMap<u16, u64>> {
+        ctx.request(req, ControlApiCmd::BlockedStats).await.into()
+    }
+
+    /// Passed and dropped packets since the datapath was loaded
+    #[oai(path = "/stats/packets", method = "get")]
+    async fn stats_packets(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+    ) -> RestResponse<PacketStats> {
+        ctx.request(req, ControlApiCmd::PacketStats).await.into()
+    }
+
+    /// Attached interfaces with the mode their XDP program runs in
+    #[oai(path = "/interfaces", method = "get")]
+    async fn interfaces(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+    ) -> RestResponse<Vec<InterfaceStatus>> {
+        ctx.request(req, ControlApiCmd::Interfaces).await.into()
+    }
+
+    /// Passed and dropped packets of each attached interface
+    #[oai(path = "/stats/interfaces", method = "get")]
+    async fn stats_interfaces(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+    ) -> RestResponse<Vec<InterfaceStats>> {
+        ctx.request(req, ControlApiCmd::InterfaceStats).await.into()
+    }
+
+    /// Sources with the most dropped packets
+    #[oai(path = "/stats/sources", method = "get")]
+    async fn stats_sources(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+        limit: Query<Option<usize>>,
+    ) -> RestResponse<Vec<SourceStats>> {
+        let limit = limit.0.unwrap_or(DEFAULT_SOURCES_LIMIT);
+        ctx.request(req, |tx| ControlApiCmd::TopSources(limit, tx))
+            .await
+            .into()
+    }
+
+    /// Average packets/sec and drops/sec per reason and per port over a window like `1m`, `5m` or `1h`
+    #[oai(path = "/stats/rates", method = "get")]
+    async fn stats_rates(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+        window: Query<Option<String>>,
+    ) -> RestResponse<Rates> {
+        match parse_window(window.0, DEFAULT_RATES_WINDOW) {
+            Ok(window) => ctx
+                .request(req, |tx| ControlApiCmd::StatsRates(window, tx))
+                .await
+                .into(),
+            Err(e) => Err(e).into(),
+        }
+    }
+
+    /// Rates between each counter snapshot over a window, oldest first, for graphs
+    #[oai(path = "/stats/history", method = "get")]
+    async fn stats_history(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+        window: Query<Option<String>>,
+    ) -> RestResponse<Vec<Rates>> {
+        match parse_window(window.0, DEFAULT_HISTORY_WINDOW) {
+            Ok(window) => ctx
+                .request(req, |tx| ControlApiCmd::StatsHistory(window, tx))
+                .await
+                .into(),
+            Err(e) => Err(e).into(),
+        }
+    }
+
+    /// Current value of every counter
+    #[oai(path = "/stats/snapshot", method = "get")]
+    async fn stats_snapshot(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+    ) -> RestResponse<Snapshot> {
+        ctx.request(req, ControlApiCmd::StatsSnapshot).await.into()
+    }
+
+    /// Entries of the rule and automatic entry maps against their capacity
+    #[oai(path = "/stats/maps", method = "get")]
+    async fn stats_maps(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+    ) -> RestResponse<Vec<MapUsage>> {
+        ctx.request(req, ControlApiCmd::MapUsage).await.into()
+    }
+
+    /// Reset every counter and the rate history
+    #[oai(path = "/stats/reset", method = "post")]
+    async fn reset_stats(&self, ctx: Data<&HttpContext>, req: &Request) -> RestResponse<String> {
+        ctx.request(req, ControlApiCmd::ResetStats).await.into()
+    }
+
+    /// Sources currently banned by the auto ban detectors
+    #[oai(path = "/autoban", method = "get")]
+    async fn list_auto_bans(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+    ) -> RestResponse<Vec<AutoBanInfo>> {
+        ctx.request(req, ControlApiCmd::ListAutoBans).await.into()
+    }
+
+    /// Lift an auto ban before it expires
+    #[oai(path = "/autoban/:ip", method = "delete")]
+    async fn lift_auto_ban(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+        ip: Path<String>,
+    ) -> RestResponse<String> {
+        ctx.request(req, |tx| ControlApiCmd::LiftAutoBan(ip.0, tx))
+            .await
+            .into()
+    }
+
+    /// Trap ports and the sources they currently ban
+    #[oai(path = "/traps", method = "get")]
+    async fn list_traps(&self, ctx: Data<&HttpContext>, req: &Request) -> RestResponse<TrapList> {
+        ctx.request(req, ControlApiCmd::ListTraps).await.into()
+    }
+
+    /// Lift a trap port ban before it expires
+    #[oai(path = "/traps/bans/:ip", method = "delete")]
+    async fn lift_trap_ban(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+        ip: Path<String>,
+    ) -> RestResponse<String> {
+        ctx.request(req, |tx| ControlApiCmd::LiftTrapBan(ip.0, tx))
+            .await
+            .into()
+    }
+
+    /// Blocklist feeds with their rule count and last refresh status
+    #[oai(path = "/feeds", method = "get")]
+    async fn list_feeds(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+    ) -> RestResponse<Vec<FeedStatus>> {
+        ctx.request(req, ControlApiCmd::ListFeeds).await.into()
+    }
+
+    /// Whitelisted dns names with their currently resolved addresses
+    #[oai(path = "/dns", method = "get")]
+    async fn list_dns_rules(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+    ) -> RestResponse<Vec<DnsRuleStatus>> {
+        ctx.request(req, ControlApiCmd::ListDnsRules).await.into()
+    }
+
+    /// Recent rule changes, newest first
+    #[oai(path = "/audit", method = "get")]
+    async fn audit_log(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+        limit: Query<Option<usize>>,
+    ) -> RestResponse<Vec<AuditRecord>> {
+        let limit = limit.0.unwrap_or(DEFAULT_AUDIT_LIMIT);
+        ctx.request(req, |tx| ControlApiCmd::AuditLog(limit, tx))
+            .await
+            .into()
+    }
+
+    /// Recent drop events, oldest first.
+    /// Pass the last seen `seq` as `after` to poll for newer events
+    #[oai(path = "/events", method = "get")]
+    async fn drop_events(
+        &self,
+        ctx: Data<&HttpContext>,
+        req: &Request,
+        after: Query<Option<u64>>,
+        limit: Query<Option<usize>>,
+    ) -> RestResponse<Vec<DropEventInfo>> {
+        let after = after.0.unwrap_or_default();
+        let limit = limit.0.unwrap_or(DEFAULT_EVENTS_LIMIT);
+        ctx.request(req, |tx| ControlApiCmd::DropEvents(after, limit, tx))
+            .await
+            .into()
+    }
+}
@@ -0,0 +1,146 @@
+use std::{collections::BTreeMap, net::Ipv4Addr};
+
+use aya::{maps::HashMap, Bpf};
+use log::{info, warn};
+use poem_openapi::Object;
+use sdf_common::IfaceKey;
+
+use crate::{config::InterfaceRules, utils};
+
+/// Replace the content of the per interface rule maps. Rules of an interface sdf isn't
+/// attached to are skipped, its index could change before it is
+pub fn apply_config(
+    bpf: &mut Bpf,
+    interfaces: &[(String, u32)],
+    rules: &BTreeMap<String, InterfaceRules>,
+) -> Result<(), String> {
+    for map in [
+        "IFACE_SRC_BLACKLIST",
+        "IFACE_SRC_WHITELIST",
+        "IFACE_PORT_BLACKLIST",
+    ] {
+        let mut entries: HashMap<_, IfaceKey, u8> =
+            HashMap::try_from(bpf.map_mut(map).unwrap()).map_err(|e| e.to_string())?;
+        let keys = entries
+            .keys()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        for key in keys {
+            entries.remove(&key).map_err(|e| e.to_string())?;
+        }
+    }
+
+    for (name, rules) in rules {
+        let Some((_, ifindex)) = interfaces.iter().find(|(iface, _)| iface == name) else {
+            warn!("rules of interface {} are ignored, it isn't attached", name);
+            continue;
+        };
+        let lists = [
+            (
+                "IFACE_SRC_BLACKLIST",
+                "source blacklist",
+                rules
+                    .source_blacklist
+                    .iter()
+                    .map(|r| (u32::from(r.value), r.to_string()))
+                    .collect::<Vec<_>>(),
+            ),
+            (
+                "IFACE_SRC_WHITELIST",
+                "source whitelist",
+                rules
+                    .source_whitelist
+                    .iter()
+                    .map(|r| (u32::from(r.value), r.to_string()))
+                    .collect(),
+            ),
+            (
+                "IFACE_PORT_BLACKLIST",
+                "port blacklist",
+                rules
+                    .port_blacklist
+                    .iter()
+                    .map(|r| (r.value as u32, r.to_string()))
+                    .collect(),
+            ),
+        ];
+        for (map, kind, values) in lists {
+            let mut entries: HashMap<_, IfaceKey, u8> =
+                HashMap::try_from(bpf.map_mut(map).unwrap()).map_err(|e| e.to_string())?;
+            for (value, rule) in values {
+                if let Err(e) = entries.insert(IfaceKey::new(*ifindex, value), 0, 0) {
+                    warn!("add {} {} rule {} error {}", name, kind, rule, e);
+                } else {
+                    info!("added {} {} rule {}", name, kind, rule);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rules of one interface currently loaded in the per interface rule maps
+#[derive(Object, Debug, Default, Clone)]
+pub struct InterfaceRuleList {
+    pub interface: String,
+    pub source_blacklist: Vec<String>,
+    pub source_whitelist: Vec<String>,
+    pub port_blacklist: Vec<u16>,
+}
+
+/// Read back the per interface rule maps, grouped by interface name
+pub fn list(
+    bpf: &Bpf,
+    interfaces: &[(String, u32)],
+) -> Result<Vec<InterfaceRuleList>, anyhow::Error> {
+    let mut lists: BTreeMap<&str, InterfaceRuleList> = BTreeMap::new();
+    for map in [
+        "IFACE_SRC_BLACKLIST",
+        "IFACE_SRC_WHITELIST",
+        "IFACE_PORT_BLACKLIST",
+    ] {
+        let entries: HashMap<_, IfaceKey, u8> = HashMap::try_from(bpf.map(map).unwrap())?;
+        for key in entries.keys() {
+            let key = key?;
+            let Some((name, _)) = interfaces.iter().find(|(_, index)| *index == key.ifindex) else {
+                continue;
+            };
+            let list = lists.entry(name).or_insert_with(|| InterfaceRuleList {
+                interface: name.clone(),
+                ..Default::default()
+            });
+            match map {
+                "IFACE_SRC_BLACKLIST" => list
+                    .source_blacklist
+                    .push(Ipv4Addr::from(key.value).to_string()),
+                "IFACE_SRC_WHITELIST" => list
+                    .source_whitelist
+                    .push(Ipv4Addr::from(key.value).to_string()),
+                _ => list.port_blacklist.push(key.value as u16),
+            }
+        }
+    }
+    Ok(lists.into_values().collect())
+}
+
+/// Tell the programs which interfaces have no Ethernet header, like tunnels, their packets
+/// start at the IP header
+pub fn set_l3_interfaces(bpf: &mut Bpf, interfaces: &[(String, u32)]) -> Result<(), anyhow::Error> {
+    let mut map: HashMap<_, u32, u8> = HashMap::try_from(bpf.map_mut("L3_INTERFACES").unwrap())?;
+    let l3: Vec<_> = interfaces
+        .iter()
+        .filter(|(name, _)| !utils::has_ethernet(name))
+        .collect();
+    // entries of a previous run are only removed when they changed, the programs still run
+    let stale = map.keys().collect::<Result<Vec<_>, _>>()?;
+    for ifindex in stale {
+        if !l3.iter().any(|(_, index)| *index == ifindex) {
+            map.remove(&ifindex)?;
+        }
+    }
+    for (name, ifindex) in l3 {
+        info!("{} has no Ethernet header", name);
+        map.insert(*ifindex, 0, 0)?;
+    }
+    Ok(())
+}
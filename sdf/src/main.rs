@@ -1,32 +1,160 @@
 use anyhow::Context;
 use aya::maps::HashMap;
-use aya::programs::{tc, SchedClassifier, TcAttachType, Xdp, XdpFlags};
-use aya::{include_bytes_aligned, Bpf};
+use aya::programs::{tc, SchedClassifier, TcAttachType, Xdp};
+use aya::{Bpf, BpfLoader};
 use aya_log::BpfLogger;
-use clap::Parser;
-use config_file::FromConfigFile;
-use log::{debug, info, warn};
+use clap::{Parser, Subcommand};
+use log::{debug, error, info, warn};
 use sdf_common::IpV4Addr;
-use tokio::sync::mpsc;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
 use tokio::{select, signal};
 
+mod attach;
+mod audit;
+mod autoban;
+mod chain;
+mod check;
 mod config;
+mod dns;
+mod events;
+mod feed;
+mod geo;
+mod grpc;
+mod history;
 mod http;
+mod iface;
+mod object;
+mod pin;
+mod scan;
+mod stats;
+mod trap;
 mod utils;
 
-use config::StaticConfig;
-use http::{start_http_server, ApiResult, ControlApiCmd, HttpCmd};
+use attach::{Datapath, InterfaceStatus, XdpMode};
+use audit::{ApiTokens, AuditLog, Caller};
+use autoban::{AutoBan, BanChange};
+use check::CheckArgs;
+use config::{StaticConfig, TlsConfig};
+use dns::{spawn_dns_resolver, DnsAllow};
+use events::{spawn_drop_event_readers, EventHub};
+use feed::{spawn_feed_refresh, Feeds};
+use geo::{spawn_geo_resolver, Geo};
+use grpc::start_grpc_server;
+use history::StatsHistory;
+use http::{
+    start_http_server, ApiError, ControlApiCmd, HttpCmd, HttpServerConfig, RuleList,
+    UnixSocketConfig,
+};
+use pin::Pinning;
+use stats::MapAlerts;
 
 #[derive(Debug, Parser)]
 struct Opt {
-    #[clap(short, long, default_value = "ens192")]
-    iface: String,
+    /// Interfaces to attach to, repeated or comma separated
+    #[clap(short, long, default_value = "ens192", value_delimiter = ',')]
+    iface: Vec<String>,
+
+    /// How the XDP program is attached, takes precedence over xdp_mode of the config file
+    #[clap(long, value_enum)]
+    xdp_mode: Option<XdpMode>,
+
+    /// Interfaces filtered by the TC ingress classifier instead of XDP, added to tc_ingress of
+    /// the config file
+    #[clap(long, value_delimiter = ',')]
+    tc_ingress: Vec<String>,
 
     #[clap(long, default_value = "0.0.0.0:3000")]
     http_port: String,
 
+    /// Don't serve the control API over TCP, only on the unix socket
+    #[clap(long, requires = "unix_socket")]
+    no_tcp: bool,
+
+    /// Also serve the control API on this unix socket, without token check
+    #[clap(long)]
+    unix_socket: Option<PathBuf>,
+
+    /// Octal file mode of the unix socket
+    #[clap(long, default_value = "660", value_parser = parse_mode)]
+    unix_socket_mode: u32,
+
+    /// Group id owning the unix socket
+    #[clap(long)]
+    unix_socket_group: Option<u32>,
+
     #[clap(long)]
     config: Option<String>,
+
+    /// PEM certificate chain, enables TLS on the control API
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the control API certificate
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM CA bundle, when set only clients with a certificate signed by it can call the control API
+    #[clap(long, requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Serve the gRPC control plane on this address, like 0.0.0.0:50051
+    #[clap(long)]
+    grpc_port: Option<SocketAddr>,
+
+    /// Append-only JSON lines file receiving an audit record for every rule change
+    #[clap(long)]
+    audit_log: Option<PathBuf>,
+
+    /// How long counter snapshots are kept, the longest window of the rate endpoints
+    #[clap(long, default_value = "1h", value_parser = humantime::parse_duration)]
+    stats_retention: Duration,
+
+    /// Rule map size, like `source_blacklist=65536`, takes precedence over map_capacities
+    /// of the config file. Can be repeated
+    #[clap(long, value_parser = parse_capacity)]
+    map_capacity: Vec<(String, u32)>,
+
+    /// Pin the maps and the XDP link in this bpffs directory, /sys/fs/bpf/sdf when no path
+    /// is given. Filtering goes on after exit and the next run picks up the pinned rules
+    #[clap(long, num_args = 0..=1, default_missing_value = pin::DEFAULT_PIN_PATH)]
+    pin: Option<PathBuf>,
+
+    /// Detach everything a `--pin` run left, its classifiers and the pins of its directory,
+    /// then exit
+    #[clap(long, requires = "pin")]
+    detach: bool,
+
+    /// Load the eBPF programs and maps from this object file instead of the one built into
+    /// the binary. It must have the programs and maps of sdf-ebpf
+    #[clap(long)]
+    bpf_object: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Validate a config file and optionally diff it against a running instance
+    Check(CheckArgs),
+}
+
+fn parse_capacity(value: &str) -> Result<(String, u32), String> {
+    let (name, entries) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected map=entries, got {}", value))?;
+    let entries = entries
+        .parse()
+        .map_err(|e| format!("invalid capacity {}: {}", entries, e))?;
+    Ok((name.to_string(), entries))
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8).map_err(|e| format!("invalid octal mode {}: {}", mode, e))
 }
 
 #[tokio::main]
@@ -35,6 +163,10 @@ async fn main() -> Result<(), anyhow::Error> {
 
     env_logger::init();
 
+    if let Some(Command::Check(args)) = opt.command {
+        std::process::exit(check::run(args).await);
+    }
+
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
     let rlim = libc::rlimit {
@@ -46,51 +178,239 @@ async fn main() -> Result<(), anyhow::Error> {
         debug!("remove limit on locked memory failed, ret is: {}", ret);
     }
 
-    // This will include your eBPF object file as raw bytes at compile-time and load it at
-    // runtime. This approach is recommended for most real-world use cases. If you would
-    // like to specify the eBPF program at runtime rather than at compile-time, you can
-    // reach for `Bpf::load_file` instead.
-    #[cfg(debug_assertions)]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/debug/sdf"
-    ))?;
-    #[cfg(not(debug_assertions))]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/release/sdf"
-    ))?;
+    // Control API settings and map sizes are only read at startup
+    let startup_config = match &opt.config {
+        Some(file) => Some(StaticConfig::load(file).context("failed to read config file")?),
+        None => None,
+    };
+    let mut capacities = startup_config
+        .as_ref()
+        .and_then(|c| c.map_capacities)
+        .unwrap_or_default();
+    for (name, entries) in &opt.map_capacity {
+        capacities.set(name, *entries).map_err(anyhow::Error::msg)?;
+    }
+    capacities.validate().map_err(anyhow::Error::msg)?;
+
+    let pinning = Pinning::new(opt.pin.as_deref())?;
+    if opt.detach {
+        return pinning.detach_all();
+    }
+    // pinned maps keep the size and layout they were created with
+    pinning.check_capacities(&capacities)?;
+    pinning.check_layout()?;
+    let mut loader = BpfLoader::new();
+    if let Some(path) = pinning.map_path() {
+        loader.map_pin_path(path);
+    }
+    for (map, entries) in capacities.maps() {
+        loader.set_max_entries(map, entries);
+    }
+    let mut bpf = object::load(
+        &mut loader,
+        opt.bpf_object.as_deref(),
+        pinning.map_path().is_some(),
+    )?;
+    pinning.set_layout(&mut bpf)?;
     if let Err(e) = BpfLogger::init(&mut bpf) {
         // This can happen if you remove all log statements from your eBPF program.
         warn!("failed to initialize eBPF logger: {}", e);
     }
 
+    // the per interface maps are keyed by index
+    let mut interfaces = vec![];
+    for name in &opt.iface {
+        let ifindex = utils::ifindex(name)
+            .ok_or_else(|| anyhow::anyhow!("interface {} doesn't exist", name))?;
+        interfaces.push((name.clone(), ifindex));
+    }
+    iface::set_l3_interfaces(&mut bpf, &interfaces)?;
+    let mut tc_ingress = opt.tc_ingress.clone();
+    if let Some(config) = &startup_config {
+        tc_ingress.extend(config.tc_ingress.iter().cloned());
+    }
+    for name in tc_ingress.iter().filter(|name| !opt.iface.contains(*name)) {
+        warn!("tc_ingress {} is ignored, it isn't attached", name);
+    }
+    let (tc_interfaces, xdp_interfaces): (Vec<_>, Vec<_>) = interfaces
+        .iter()
+        .partition(|(name, _)| tc_ingress.contains(name));
+
     info!("loading sdf_ingress");
+    let xdp_mode = opt
+        .xdp_mode
+        .or(startup_config.as_ref().and_then(|c| c.xdp_mode))
+        .unwrap_or(XdpMode::Auto);
+    let xdp_chain = startup_config
+        .as_ref()
+        .map(|c| c.xdp_chain.clone())
+        .unwrap_or_default();
+    // in place before sdf_ingress is attached, and kept loaded for the life of the daemon
+    let _chained_objects = chain::load(&mut bpf, &xdp_interfaces, &xdp_chain)?;
     let program_ingress: &mut Xdp = bpf.program_mut("sdf_ingress").unwrap().try_into()?;
     program_ingress.load()?;
-    program_ingress.attach(&opt.iface, XdpFlags::default())
-        .context("failed to attach the XDP program with default flags - try changing XdpFlags::default() to XdpFlags::SKB_MODE")?;
+    let mut interface_status = vec![];
+    for (name, ifindex) in xdp_interfaces {
+        let mode = attach::attach_xdp(&pinning, program_ingress, name, xdp_mode)?;
+        // the interface used the TC datapath in the previous run
+        pinning.detach_classifier(name, TcAttachType::Ingress);
+        interface_status.push(InterfaceStatus {
+            interface: name.clone(),
+            ifindex: *ifindex,
+            datapath: Datapath::Xdp,
+            xdp_mode: mode,
+            xdp_chain: xdp_chain.get(name).map(|c| c.program.clone()),
+        });
+    }
+
+    info!("loading sdf_tc_ingress");
+    let program_tc_ingress: &mut SchedClassifier =
+        bpf.program_mut("sdf_tc_ingress").unwrap().try_into()?;
+    program_tc_ingress.load()?;
+    for (name, ifindex) in tc_interfaces {
+        let _ = tc::qdisc_add_clsact(name);
+        pinning.attach_classifier(program_tc_ingress, name, TcAttachType::Ingress)?;
+        // the interface used XDP in the previous run, both filtered until now
+        pinning.unpin_xdp(name);
+        info!("attached sdf_tc_ingress to {}", name);
+        interface_status.push(InterfaceStatus {
+            interface: name.clone(),
+            ifindex: *ifindex,
+            datapath: Datapath::Tc,
+            xdp_mode: None,
+            xdp_chain: None,
+        });
+    }
+    interface_status
+        .sort_by_key(|status| opt.iface.iter().position(|name| *name == status.interface));
 
     info!("loading sdf_egress");
-    let _ = tc::qdisc_add_clsact(&opt.iface);
     let program_egress: &mut SchedClassifier = bpf.program_mut("sdf_egress").unwrap().try_into()?;
     program_egress.load()?;
-    program_egress.attach(&opt.iface, TcAttachType::Egress)?;
+    for iface in &opt.iface {
+        let _ = tc::qdisc_add_clsact(iface);
+        pinning.attach_classifier(program_egress, iface, TcAttachType::Egress)?;
+    }
+    // interfaces of the previous run that were dropped from --iface
+    pinning.remove_stale(&opt.iface);
 
     info!("loaded ebpf programs");
 
-    let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
+    let events = Arc::new(EventHub::new());
+    spawn_drop_event_readers(&mut bpf, events.clone())?;
+
+    let mut interval = tokio::time::interval(stats::STATS_INTERVAL);
+    let mut history = StatsHistory::new(opt.stats_retention);
+    let mut map_alerts = MapAlerts::default();
 
     let (tx, mut rx) = mpsc::channel(100);
 
+    let mut audit = AuditLog::open(opt.audit_log.as_deref()).context("failed to open audit log")?;
+
+    // TLS from the command line takes precedence over the config file
+    let tls = match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => Some(TlsConfig {
+            cert: cert.clone(),
+            key: key.clone(),
+            client_ca: opt.tls_client_ca.clone(),
+        }),
+        _ => startup_config.as_ref().and_then(|c| c.tls.clone()),
+    };
+    let mut auto_ban = AutoBan::new(
+        startup_config
+            .as_ref()
+            .and_then(|c| c.auto_ban.clone())
+            .unwrap_or_default(),
+        events.subscribe(),
+    );
+    if pinning.reused() {
+        let lifted = auto_ban.clear_pinned(&mut bpf)?;
+        info!("lifted {} auto bans of the previous run", lifted);
+    }
+    let feed_configs = startup_config
+        .as_ref()
+        .map(|c| c.feeds.clone())
+        .unwrap_or_default();
+    let mut feeds = Feeds::new(&feed_configs);
+    if pinning.reused() {
+        feeds.adopt(&mut bpf)?;
+    }
+    let (feed_tx, mut feed_rx) = mpsc::channel(16);
+    spawn_feed_refresh(feed_configs, feed_tx);
+    let (geo_tx, mut geo_rx) = mpsc::channel(1);
+    let mut geo = match startup_config.as_ref().and_then(|c| c.geo.clone()) {
+        Some(config) => {
+            geo::validate(&config).map_err(anyhow::Error::msg)?;
+            if let Some(path) = geo::missing_databases(&config).first() {
+                anyhow::bail!("geo database {} doesn't exist", path.display());
+            }
+            events.set_geo_rules(config.rules.iter().map(|rule| rule.name()).collect());
+            let geo = Geo::new(&mut bpf, &config)?;
+            spawn_geo_resolver(config, geo_tx);
+            Some(geo)
+        }
+        None => {
+            if pinning.reused() {
+                geo::clear(&mut bpf)?;
+            }
+            None
+        }
+    };
+    let mut dns = DnsAllow::default();
+    let (dns_names_tx, dns_names_rx) = watch::channel(vec![]);
+    let (dns_tx, mut dns_rx) = mpsc::channel(16);
+    if let Err(e) = spawn_dns_resolver(dns_names_rx, dns_tx) {
+        warn!(
+            "read system resolver config error {}, dns rules are disabled",
+            e
+        );
+    }
+    let startup_dns_names = startup_config
+        .as_ref()
+        .map(|c| c.source_whitelist_dns.clone())
+        .unwrap_or_default();
+    let api_tokens = ApiTokens::new(startup_config.map(|c| c.api_tokens).unwrap_or_default());
+
+    let http_config = HttpServerConfig {
+        tcp: (!opt.no_tcp).then(|| opt.http_port.clone()),
+        tls: tls.clone(),
+        unix_socket: opt.unix_socket.clone().map(|path| UnixSocketConfig {
+            path,
+            mode: opt.unix_socket_mode,
+            group: opt.unix_socket_group,
+        }),
+        api_tokens: api_tokens.clone(),
+    };
+    let http_server = start_http_server(tx.clone(), http_config)
+        .await
+        .context("failed to start the control api")?;
     tokio::spawn(async move {
-        start_http_server(tx, &opt.http_port)
-            .await
-            .expect("must work");
+        if let Err(e) = http_server.await {
+            error!("control api error {}", e);
+        }
     });
 
+    if let Some(addr) = opt.grpc_port {
+        let grpc_server = start_grpc_server(tx, addr, tls, api_tokens)
+            .await
+            .context("failed to start the grpc control plane")?;
+        tokio::spawn(async move {
+            if let Err(e) = grpc_server.await {
+                error!("grpc control plane error {}", e);
+            }
+        });
+    }
+
     // Reading data
-    let reload_config = |bpf: &mut Bpf| -> Result<(), String> {
+    // Returns the dns names of the whitelist, their addresses are added by DnsAllow
+    let reload_config = |bpf: &mut Bpf| -> Result<Vec<String>, String> {
+        let mut dns_names = vec![];
         if let Some(file) = &opt.config {
-            let config = StaticConfig::from_config_file(&file).map_err(|e| e.to_string())?;
+            let config = StaticConfig::load(file).map_err(|e| e.to_string())?;
+            let errors = check::capacity_errors(&config, &capacities);
+            if !errors.is_empty() {
+                return Err(errors.join(", "));
+            }
 
             let mut src_blacklist: HashMap<_, u32, u8> =
                 HashMap::try_from(bpf.map_mut("SRC_BLACKLIST").unwrap())
@@ -100,11 +420,12 @@ async fn main() -> Result<(), anyhow::Error> {
                 src_blacklist.remove(&ip);
             }
 
-            for ip in config.source_blacklist {
+            for rule in config.source_blacklist {
+                let ip = rule.value;
                 if let Err(e) = src_blacklist.insert(u32::from(IpV4Addr(ip.octets())), 0, 0) {
-                    warn!("add source blacklist rule {} error {}", ip, e);
+                    warn!("add source blacklist rule {} error {}", rule, e);
                 } else {
-                    info!("added source blacklist rule {}", ip);
+                    info!("added source blacklist rule {}", rule);
                 }
             }
 
@@ -116,11 +437,12 @@ async fn main() -> Result<(), anyhow::Error> {
                 src_whitelist.remove(&ip);
             }
 
-            for ip in config.source_whitelist {
+            for rule in config.source_whitelist {
+                let ip = rule.value;
                 if let Err(e) = src_whitelist.insert(u32::from(IpV4Addr(ip.octets())), 0, 0) {
-                    warn!("add source whitelist rule {} error {}", ip, e);
+                    warn!("add source whitelist rule {} error {}", rule, e);
                 } else {
-                    info!("added source whitelist rule {}", ip);
+                    info!("added source whitelist rule {}", rule);
                 }
             }
 
@@ -135,113 +457,283 @@ async fn main() -> Result<(), anyhow::Error> {
                 port_blacklist.remove(&port);
             }
 
-            for port in config.port_blacklist {
-                if let Err(e) = port_blacklist.insert(port, 0, 0) {
-                    warn!("add port blacklist rule {} error {}", port, e);
+            for rule in config.port_blacklist {
+                if let Err(e) = port_blacklist.insert(rule.value, 0, 0) {
+                    warn!("add port blacklist rule {} error {}", rule, e);
                 } else {
-                    info!("added port blacklist rule {}", port);
+                    info!("added port blacklist rule {}", rule);
                 }
             }
+
+            scan::apply_config(bpf, config.scan_detection.as_ref())?;
+            trap::apply_config(bpf, config.trap_ports.as_ref())?;
+            iface::apply_config(bpf, &interfaces, &config.interfaces)?;
+            dns_names = config.source_whitelist_dns;
         }
-        Ok(())
+        Ok(dns_names)
     };
-    reload_config(&mut bpf).expect("Config file need to valid");
+    // Pinned maps still hold the rules of the previous run, the ones added through the API
+    // included, the config file replaces them on the next reload
+    let dns_names = if pinning.reused() {
+        info!("keeping the pinned rules, reload to apply the config file");
+        startup_dns_names
+    } else {
+        let res = reload_config(&mut bpf).map_err(ApiError::ReloadFailed);
+        audit.record(&Caller::system(), "reload", None, res.as_ref().err());
+        res.map_err(|e| anyhow::anyhow!("invalid config file {}", e))?
+    };
+    for usage in stats::map_usage(&bpf, &capacities)? {
+        info!(
+            "{} holds {} of {} entries",
+            usage.name, usage.entries, usage.max_entries
+        );
+    }
+    dns.reload(&mut bpf, &dns_names)?;
+    if pinning.reused() {
+        dns.adopt(&mut bpf)?;
+    }
+    let _ = dns_names_tx.send(dns_names);
     // End of reading data
 
     info!("Waiting for Ctrl-C...");
     loop {
         select! {
             event = rx.recv() => match event.expect("should Some") {
-                HttpCmd::ControlApi(ControlApiCmd::Reload(res)) => {
-                    if let Err(e) = reload_config(&mut bpf) {
-                        res.send(ApiResult::error(&e)).expect("Should work");
-                    } else {
-                        res.send(ApiResult::success("RELOADED".to_string())).expect("Should work");
-                    }
+                HttpCmd::ControlApi(caller, ControlApiCmd::Reload(res)) => {
+                    let result = match reload_config(&mut bpf) {
+                        Ok(dns_names) => {
+                            if let Err(e) = dns.reload(&mut bpf, &dns_names) {
+                                warn!("restore dns whitelist error {}", e);
+                            }
+                            let _ = dns_names_tx.send(dns_names);
+                            Ok("RELOADED".to_string())
+                        }
+                        Err(e) => Err(ApiError::ReloadFailed(e)),
+                    };
+                    audit.record(&caller, "reload", None, result.as_ref().err());
+                    res.send(result).expect("Should work");
                 },
-                HttpCmd::ControlApi(ControlApiCmd::BlockedStats(res)) => {
-                    let blocked: HashMap<_, u16, u64> = HashMap::try_from(bpf.map_mut("BLOCKED_STATS").unwrap())?;
-                    let mut stats = std::collections::HashMap::new();
-                    for row in blocked.iter() {
-                        if let Ok((port, count)) = row {
-                            stats.insert(port, count);
+                HttpCmd::ControlApi(_, ControlApiCmd::ListRules(res)) => {
+                    let src_blacklist: HashMap<_, u32, u8> = HashMap::try_from(bpf.map("SRC_BLACKLIST").unwrap())?;
+                    let src_whitelist: HashMap<_, u32, u8> = HashMap::try_from(bpf.map("SRC_WHITELIST").unwrap())?;
+                    let port_blacklist: HashMap<_, u16, u8> = HashMap::try_from(bpf.map("PORT_BLACKLIST").unwrap())?;
+                    let auto_whitelist: HashMap<_, u32, u8> = HashMap::try_from(bpf.map("AUTO_WHITELIST").unwrap())?;
+                    let rules = RuleList {
+                        source_blacklist: src_blacklist.keys().flatten().map(|ip| Ipv4Addr::from(ip).to_string()).collect(),
+                        source_whitelist: src_whitelist.keys().flatten().map(|ip| Ipv4Addr::from(ip).to_string()).collect(),
+                        port_blacklist: port_blacklist.keys().flatten().collect(),
+                        auto_whitelist: auto_whitelist.keys().flatten().map(|ip| Ipv4Addr::from(ip).to_string()).collect(),
+                        dns_whitelist: dns.owned().map(|ip| Ipv4Addr::from(ip).to_string()).collect(),
+                        interfaces: iface::list(&bpf, &interfaces)?,
+                    };
+                    res.send(Ok(rules)).expect("Should work");
+                }
+                HttpCmd::ControlApi(_, ControlApiCmd::BlockedStats(res)) => {
+                    res.send(Ok(stats::blocked_stats(&bpf)?)).expect("Should work");
+                }
+                HttpCmd::ControlApi(_, ControlApiCmd::PacketStats(res)) => {
+                    res.send(Ok(stats::packet_stats(&bpf)?)).expect("Should work");
+                }
+                HttpCmd::ControlApi(_, ControlApiCmd::Interfaces(res)) => {
+                    res.send(Ok(interface_status.clone())).expect("Should work");
+                }
+                HttpCmd::ControlApi(_, ControlApiCmd::InterfaceStats(res)) => {
+                    res.send(Ok(stats::interface_stats(&bpf, &interfaces)?)).expect("Should work");
+                }
+                HttpCmd::ControlApi(_, ControlApiCmd::TopSources(limit, res)) => {
+                    res.send(Ok(stats::top_sources(&bpf, limit)?)).expect("Should work");
+                }
+                HttpCmd::ControlApi(_, ControlApiCmd::StatsRates(window, res)) => {
+                    res.send(Ok(history.rates(window))).expect("Should work");
+                }
+                HttpCmd::ControlApi(_, ControlApiCmd::StatsHistory(window, res)) => {
+                    res.send(Ok(history.history(window))).expect("Should work");
+                }
+                HttpCmd::ControlApi(_, ControlApiCmd::MapUsage(res)) => {
+                    res.send(Ok(stats::map_usage(&bpf, &capacities)?)).expect("Should work");
+                }
+                HttpCmd::ControlApi(_, ControlApiCmd::StatsSnapshot(res)) => {
+                    res.send(Ok(stats::snapshot(&bpf)?)).expect("Should work");
+                }
+                HttpCmd::ControlApi(caller, ControlApiCmd::ResetStats(res)) => {
+                    let result = match stats::reset(&mut bpf) {
+                        Ok(()) => {
+                            history.clear();
+                            info!("reset counters");
+                            Ok("RESET".to_string())
                         }
+                        Err(e) => {
+                            warn!("reset counters error {}", e);
+                            Err(ApiError::CannotUpdateMap)
+                        }
+                    };
+                    audit.record(&caller, "reset_stats", None, result.as_ref().err());
+                    res.send(result).expect("Should work");
+                }
+                HttpCmd::ControlApi(_, ControlApiCmd::ListAutoBans(res)) => {
+                    res.send(Ok(auto_ban.list())).expect("Should work");
+                }
+                HttpCmd::ControlApi(caller, ControlApiCmd::LiftAutoBan(ip, res)) => {
+                    let result = auto_ban.lift(&mut bpf, &ip);
+                    if result.is_ok() {
+                        info!("lifted auto ban {}", ip);
                     }
-                    res.send(ApiResult::success(stats)).expect("Should work");
+                    audit.record(&caller, "lift_auto_ban", Some(ip), result.as_ref().err());
+                    res.send(result).expect("Should work");
                 }
-                HttpCmd::ControlApi(ControlApiCmd::SetBlacklistSourceRule(ip, res)) => {
+                HttpCmd::ControlApi(_, ControlApiCmd::ListTraps(res)) => {
+                    res.send(Ok(trap::list(&bpf)?)).expect("Should work");
+                }
+                HttpCmd::ControlApi(caller, ControlApiCmd::LiftTrapBan(ip, res)) => {
+                    let result = trap::lift(&mut bpf, &ip);
+                    if result.is_ok() {
+                        info!("lifted trap ban {}", ip);
+                    }
+                    audit.record(&caller, "lift_trap_ban", Some(ip), result.as_ref().err());
+                    res.send(result).expect("Should work");
+                }
+                HttpCmd::ControlApi(_, ControlApiCmd::ListFeeds(res)) => {
+                    res.send(Ok(feeds.status())).expect("Should work");
+                }
+                HttpCmd::ControlApi(_, ControlApiCmd::ListDnsRules(res)) => {
+                    res.send(Ok(dns.status())).expect("Should work");
+                }
+                HttpCmd::ControlApi(_, ControlApiCmd::AuditLog(limit, res)) => {
+                    res.send(Ok(audit.recent(limit))).expect("Should work");
+                }
+                HttpCmd::ControlApi(_, ControlApiCmd::DropEvents(after, limit, res)) => {
+                    res.send(Ok(events.recent(after, limit))).expect("Should work");
+                }
+                HttpCmd::ControlApi(_, ControlApiCmd::SubscribeDropEvents(res)) => {
+                    res.send(Ok(events.subscribe())).expect("Should work");
+                }
+                HttpCmd::ControlApi(caller, ControlApiCmd::SetBlacklistSourceRule(ip, res)) => {
                     let mut src_blacklist: HashMap<_, u32, u8> = HashMap::try_from(bpf.map_mut("SRC_BLACKLIST").unwrap())?;
-                    if let Some(ip) = utils::to_ipv4(&ip) {
-                        if src_blacklist.insert(ip, 0, 0).is_ok() {
+                    let result = if let Some(addr) = utils::to_ipv4(&ip) {
+                        if src_blacklist.insert(addr, 0, 0).is_ok() {
+                            auto_ban.forget(&mut bpf, addr);
                             info!("added source blacklist {}", ip);
-                            res.send(ApiResult::success("ADDED".to_string())).expect("Should work");
+                            Ok("ADDED".to_string())
                         } else {
-                            res.send(ApiResult::error("CANNOT_ADD_TO_MAP")).expect("Should work");
+                            Err(stats::insert_error(&bpf, "SRC_BLACKLIST", &capacities))
                         }
                     } else {
-                        res.send(ApiResult::error("ONLY_SUPPORT_IP_V4")).expect("Should work");
-                    }
+                        Err(ApiError::OnlySupportIpV4)
+                    };
+                    audit.record(&caller, "set_blacklist_source", Some(ip), result.as_ref().err());
+                    res.send(result).expect("Should work");
                 },
-                HttpCmd::ControlApi(ControlApiCmd::DelBlacklistSourceRule(ip, res)) => {
+                HttpCmd::ControlApi(caller, ControlApiCmd::DelBlacklistSourceRule(ip, res)) => {
                     let mut src_blacklist: HashMap<_, u32, u8> = HashMap::try_from(bpf.map_mut("SRC_BLACKLIST").unwrap())?;
-                    if let Some(ip) = utils::to_ipv4(&ip) {
-                        if src_blacklist.remove(&ip).is_ok() {
+                    let result = if let Some(addr) = utils::to_ipv4(&ip) {
+                        if src_blacklist.remove(&addr).is_ok() {
                             info!("removed source blacklist {}", ip);
-                            res.send(ApiResult::success("REMOVED".to_string())).expect("Should work");
+                            Ok("REMOVED".to_string())
                         } else {
-                            res.send(ApiResult::error("IP_NOT_FOUND")).expect("Should work");
+                            Err(ApiError::IpNotFound)
                         }
                     } else {
-                        res.send(ApiResult::error("ONLY_SUPPORT_IP_V4")).expect("Should work");
-                    }
+                        Err(ApiError::OnlySupportIpV4)
+                    };
+                    audit.record(&caller, "del_blacklist_source", Some(ip), result.as_ref().err());
+                    res.send(result).expect("Should work");
                 },
-                HttpCmd::ControlApi(ControlApiCmd::SetWhitelistSourceRule(ip, res)) => {
+                HttpCmd::ControlApi(caller, ControlApiCmd::SetWhitelistSourceRule(ip, res)) => {
                     let mut src_whitelist: HashMap<_, u32, u8> = HashMap::try_from(bpf.map_mut("SRC_WHITELIST").unwrap())?;
-                    if let Some(ip) = utils::to_ipv4(&ip) {
-                        if src_whitelist.insert(ip, 0, 0).is_ok() {
+                    let result = if let Some(addr) = utils::to_ipv4(&ip) {
+                        if src_whitelist.insert(addr, 0, 0).is_ok() {
                             info!("added source whitelist {}", ip);
-                            res.send(ApiResult::success("ADDED".to_string())).expect("Should work");
+                            Ok("ADDED".to_string())
                         } else {
-                            res.send(ApiResult::error("CANNOT_ADD_TO_MAP")).expect("Should work");
+                            Err(stats::insert_error(&bpf, "SRC_WHITELIST", &capacities))
                         }
                     } else {
-                        res.send(ApiResult::error("ONLY_SUPPORT_IP_V4")).expect("Should work");
-                    }
+                        Err(ApiError::OnlySupportIpV4)
+                    };
+                    audit.record(&caller, "set_whitelist_source", Some(ip), result.as_ref().err());
+                    res.send(result).expect("Should work");
                 },
-                HttpCmd::ControlApi(ControlApiCmd::DelWhitelistSourceRule(ip, res)) => {
+                HttpCmd::ControlApi(caller, ControlApiCmd::DelWhitelistSourceRule(ip, res)) => {
                     let mut src_whitelist: HashMap<_, u32, u8> = HashMap::try_from(bpf.map_mut("SRC_WHITELIST").unwrap())?;
-                    if let Some(ip) = utils::to_ipv4(&ip) {
-                        if src_whitelist.remove(&ip).is_ok() {
+                    let result = if let Some(addr) = utils::to_ipv4(&ip) {
+                        let removed = src_whitelist.remove(&addr).is_ok();
+                        let mut auto_whitelist: HashMap<_, u32, u8> = HashMap::try_from(bpf.map_mut("AUTO_WHITELIST").unwrap())?;
+                        if auto_whitelist.remove(&addr).is_ok() || removed {
                             info!("removed source whitelist {}", ip);
-                            res.send(ApiResult::success("REMOVED".to_string())).expect("Should work");
+                            Ok("REMOVED".to_string())
                         } else {
-                            res.send(ApiResult::error("IP_NOT_FOUND")).expect("Should work");
+                            Err(ApiError::IpNotFound)
                         }
                     } else {
-                        res.send(ApiResult::error("ONLY_SUPPORT_IP_V4")).expect("Should work");
-                    }
+                        Err(ApiError::OnlySupportIpV4)
+                    };
+                    audit.record(&caller, "del_whitelist_source", Some(ip), result.as_ref().err());
+                    res.send(result).expect("Should work");
                 },
-                HttpCmd::ControlApi(ControlApiCmd::SetBlacklistPortRule(port, res)) => {
+                HttpCmd::ControlApi(caller, ControlApiCmd::SetBlacklistPortRule(port, res)) => {
                     let mut port_blacklist: HashMap<_, u16, u8> = HashMap::try_from(bpf.map_mut("PORT_BLACKLIST").unwrap())?;
-                    if port_blacklist.insert(port, 0, 0).is_ok() {
+                    let result = if port_blacklist.insert(port, 0, 0).is_ok() {
                         info!("added port blacklist {}", port);
-                        res.send(ApiResult::success("ADDED".to_string())).expect("Should work");
+                        Ok("ADDED".to_string())
                     } else {
-                        res.send(ApiResult::error("CANNOT_ADD_TO_MAP")).expect("Should work");
-                    }
+                        Err(stats::insert_error(&bpf, "PORT_BLACKLIST", &capacities))
+                    };
+                    audit.record(&caller, "set_blacklist_port", Some(port.to_string()), result.as_ref().err());
+                    res.send(result).expect("Should work");
                 },
-                HttpCmd::ControlApi(ControlApiCmd::DelBlacklistPortRule(port, res)) => {
+                HttpCmd::ControlApi(caller, ControlApiCmd::DelBlacklistPortRule(port, res)) => {
                     let mut port_blacklist: HashMap<_, u16, u8> = HashMap::try_from(bpf.map_mut("PORT_BLACKLIST").unwrap())?;
-                    if port_blacklist.remove(&port).is_ok() {
+                    let result = if port_blacklist.remove(&port).is_ok() {
                         info!("removed port blacklist {}", port);
-                        res.send(ApiResult::success("REMOVED".to_string())).expect("Should work");
+                        Ok("REMOVED".to_string())
                     } else {
-                        res.send(ApiResult::error("IP_NOT_FOUND")).expect("Should work");
-                    }
+                        Err(ApiError::PortNotFound)
+                    };
+                    audit.record(&caller, "del_blacklist_port", Some(port.to_string()), result.as_ref().err());
+                    res.send(result).expect("Should work");
                 },
             },
+            Some(update) = feed_rx.recv() => {
+                if let Err(e) = feeds.apply(&mut bpf, update) {
+                    warn!("apply feed error {}", e);
+                }
+            },
+            Some(update) = dns_rx.recv() => {
+                if let Err(e) = dns.apply(&mut bpf, update) {
+                    warn!("apply dns rule error {}", e);
+                }
+            },
+            Some(update) = geo_rx.recv() => {
+                if let Some(geo) = geo.as_mut() {
+                    if let Err(e) = geo.apply(&mut bpf, update) {
+                        warn!("apply geo rules error {}", e);
+                    }
+                }
+            },
             _ = interval.tick() => {
-
+                match stats::snapshot(&bpf) {
+                    Ok(snapshot) => history.push(snapshot),
+                    Err(e) => warn!("snapshot counters error {}", e),
+                }
+                match auto_ban.tick(&mut bpf) {
+                    Ok(changes) => for change in changes {
+                        match change {
+                            BanChange::Banned(ban) => {
+                                info!("auto banned {} by {} until {}", ban.source, ban.detector, ban.expires_at);
+                                audit.record(&Caller::system(), "auto_ban", Some(format!("{} {}", ban.source, ban.detector)), None);
+                            }
+                            BanChange::Expired(ip) => {
+                                info!("auto ban of {} expired", ip);
+                                audit.record(&Caller::system(), "auto_ban_expired", Some(ip.to_string()), None);
+                            }
+                        }
+                    },
+                    Err(e) => warn!("auto ban error {}", e),
+                }
+                match stats::map_usage(&bpf, &capacities) {
+                    Ok(usage) => map_alerts.update(&usage),
+                    Err(e) => warn!("read map usage error {}", e),
+                }
             },
             _ = signal::ctrl_c() => {
                 break;
@@ -249,6 +741,9 @@ async fn main() -> Result<(), anyhow::Error> {
         }
     }
     info!("Exiting...");
+    if let Some(path) = &opt.unix_socket {
+        let _ = std::fs::remove_file(path);
+    }
 
     Ok(())
 }
@@ -0,0 +1,133 @@
+use std::{borrow::Cow, path::Path};
+
+use ::object::{File, Object as _, ObjectSection as _, ObjectSymbol as _, SymbolKind};
+use anyhow::{bail, Context};
+use aya::{include_bytes_aligned, Bpf, BpfLoader};
+use aya_obj::{programs::ProgramSection, Object};
+use log::info;
+use sdf_common::MAP_NAMES;
+
+/// Programs sdf attaches, with the type it loads them as
+const PROGRAMS: [(&str, ProgramKind); 3] = [
+    ("sdf_ingress", ProgramKind::Xdp),
+    ("sdf_tc_ingress", ProgramKind::Classifier),
+    ("sdf_egress", ProgramKind::Classifier),
+];
+
+/// Size of the map definitions of aya-bpf, the pinning type is their last field
+const MAP_DEF_LEN: u64 = 28;
+const MAP_DEF_PINNING: usize = 24;
+/// PinningType::ByName
+const PIN_BY_NAME: u32 = 1;
+
+#[derive(Clone, Copy)]
+enum ProgramKind {
+    Xdp,
+    Classifier,
+}
+
+impl ProgramKind {
+    fn matches(self, section: &ProgramSection) -> bool {
+        matches!(
+            (self, section),
+            (Self::Xdp, ProgramSection::Xdp { .. })
+                | (Self::Classifier, ProgramSection::SchedClassifier { .. })
+        )
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Xdp => "XDP",
+            Self::Classifier => "TC classifier",
+        }
+    }
+}
+
+/// Load the eBPF object at `path`, or the one built with this binary, and check it has the
+/// programs and maps sdf uses before any of them is touched. With `pinned` the maps are
+/// pinned by name in the directory given to the loader, or reused from there
+pub fn load(
+    loader: &mut BpfLoader,
+    path: Option<&Path>,
+    pinned: bool,
+) -> Result<Bpf, anyhow::Error> {
+    let mut data = match path {
+        Some(path) => {
+            info!("loading eBPF object {}", path.display());
+            let data = std::fs::read(path)
+                .with_context(|| format!("failed to read eBPF object {}", path.display()))?;
+            validate(&data)
+                .with_context(|| format!("{} isn't an sdf eBPF object", path.display()))?;
+            Cow::Owned(data)
+        }
+        None => Cow::Borrowed(embedded()),
+    };
+    if pinned {
+        data = Cow::Owned(pin_maps(&data).context("failed to pin the maps of the eBPF object")?);
+    }
+    let Some(path) = path else {
+        return Ok(loader.load(&data)?);
+    };
+    loader
+        .load(&data)
+        .with_context(|| format!("failed to load eBPF object {}", path.display()))
+}
+
+/// Copy of the object with every map definition pinned by name. The object declares them
+/// unpinned so a run without `--pin` never touches bpffs
+fn pin_maps(data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let file = File::parse(data)?;
+    let mut pinned = data.to_vec();
+    for symbol in file.symbols() {
+        let (SymbolKind::Data, Some(index)) = (symbol.kind(), symbol.section_index()) else {
+            continue;
+        };
+        let section = file.section_by_index(index)?;
+        let name = section.name()?;
+        if name != "maps" && !name.starts_with("maps/") {
+            continue;
+        }
+        let Some((offset, _)) = section.file_range() else {
+            continue;
+        };
+        if symbol.size() < MAP_DEF_LEN {
+            bail!("map {} has an unknown definition", symbol.name()?);
+        }
+        let pinning = (offset + symbol.address()) as usize + MAP_DEF_PINNING;
+        pinned[pinning..pinning + 4].copy_from_slice(&PIN_BY_NAME.to_le_bytes());
+    }
+    Ok(pinned)
+}
+
+/// Only needed for `--bpf-object`, the embedded object is built from the same tree. The ELF
+/// is only parsed, so nothing is created or pinned for an object that isn't sdf's
+fn validate(data: &[u8]) -> Result<(), anyhow::Error> {
+    let object = Object::parse(data)?;
+    let mut missing = vec![];
+    for (name, kind) in PROGRAMS {
+        match object.programs.get(name) {
+            Some(program) if kind.matches(&program.section) => {}
+            Some(_) => missing.push(format!("{} isn't a {} program", name, kind.as_str())),
+            None => missing.push(format!("no program {}", name)),
+        }
+    }
+    for name in MAP_NAMES {
+        if !object.maps.contains_key(name) {
+            missing.push(format!("no map {}", name));
+        }
+    }
+    if !missing.is_empty() {
+        bail!("{}", missing.join(", "));
+    }
+    Ok(())
+}
+
+// This will include your eBPF object file as raw bytes at compile-time and load it at
+// runtime. This approach is recommended for most real-world use cases.
+fn embedded() -> &'static [u8] {
+    #[cfg(debug_assertions)]
+    let data = include_bytes_aligned!("../../target/bpfel-unknown-none/debug/sdf");
+    #[cfg(not(debug_assertions))]
+    let data = include_bytes_aligned!("../../target/bpfel-unknown-none/release/sdf");
+    data
+}
@@ -0,0 +1,306 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use aya::{
+    maps::{Array, Map, MapData, MapInfo},
+    programs::{
+        links::{FdLink, Link, PinnedLink},
+        tc::{SchedClassifierLink, TcOptions},
+        xdp::XdpLink,
+        SchedClassifier, TcAttachType, Xdp, XdpFlags,
+    },
+    Bpf,
+};
+use log::{info, warn};
+use sdf_common::MAP_LAYOUT_VERSION;
+
+use crate::config::MapCapacities;
+
+/// Maps are pinned by name in this directory when `--pin` is given without a path
+pub const DEFAULT_PIN_PATH: &str = "/sys/fs/bpf/sdf";
+/// A persistent run attaches its classifiers with one of these priorities, the other one
+/// being the filter of the previous run
+const TC_PRIORITIES: [u16; 2] = [0xc000, 0xc001];
+const TC_HANDLE: u32 = 1;
+/// The XDP link of an interface is pinned as this prefix followed by the interface name
+const XDP_LINK_PREFIX: &str = "sdf_ingress_";
+
+/// Where the maps and the XDP link live in bpffs with `--pin`. Without it bpffs isn't
+/// touched, the maps and programs live as long as the daemon does.
+pub struct Pinning {
+    path: Option<PathBuf>,
+    reused: bool,
+}
+
+impl Pinning {
+    pub fn new(path: Option<&Path>) -> Result<Self, anyhow::Error> {
+        let Some(path) = path else {
+            return Ok(Self {
+                path: None,
+                reused: false,
+            });
+        };
+        std::fs::create_dir_all(path)
+            .with_context(|| format!("failed to create pin directory {}", path.display()))?;
+        // any map of the layout tells whether a previous run left them
+        let reused = path.join("SRC_BLACKLIST").exists();
+        if reused {
+            info!("reusing the maps pinned in {}", path.display());
+        }
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            reused,
+        })
+    }
+
+    /// Directory given to the loader, pinned maps found there are reused instead of created
+    pub fn map_path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The maps come from a previous run and still hold its rules
+    pub fn reused(&self) -> bool {
+        self.reused
+    }
+
+    /// Refuse pinned rule maps created with another capacity, the loader would reuse them
+    /// with their old size
+    pub fn check_capacities(&self, capacities: &MapCapacities) -> Result<(), anyhow::Error> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        for (name, entries) in capacities.maps() {
+            let pin = path.join(name);
+            if !pin.exists() {
+                continue;
+            }
+            let max_entries = MapInfo::from_pin(&pin)
+                .with_context(|| format!("failed to read pinned map {}", pin.display()))?
+                .max_entries();
+            if max_entries != entries {
+                bail!(
+                    "{} is pinned with {} entries but its capacity is {}, set it back or stop sdf and remove {} to resize it",
+                    name,
+                    max_entries,
+                    entries,
+                    pin.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuse maps pinned by a build with another layout, before the loader reuses them.
+    /// Builds older than MAP_LAYOUT pinned the first layout
+    pub fn check_layout(&self) -> Result<(), anyhow::Error> {
+        let (Some(path), true) = (&self.path, self.reused) else {
+            return Ok(());
+        };
+        let pin = path.join("MAP_LAYOUT");
+        let version = if pin.exists() {
+            let map = MapData::from_pin(&pin)
+                .with_context(|| format!("failed to read pinned map {}", pin.display()))?;
+            let layout: Array<_, u32> = Array::try_from(Map::Array(map))?;
+            layout.get(&0, 0)?
+        } else {
+            1
+        };
+        if version != MAP_LAYOUT_VERSION {
+            bail!(
+                "the maps pinned in {} have layout {}, this build needs {}, stop sdf and remove them to start over",
+                path.display(),
+                version,
+                MAP_LAYOUT_VERSION
+            );
+        }
+        Ok(())
+    }
+
+    /// Record the layout in MAP_LAYOUT once loaded, for the next run
+    pub fn set_layout(&self, bpf: &mut Bpf) -> Result<(), anyhow::Error> {
+        let mut layout: Array<_, u32> = Array::try_from(bpf.map_mut("MAP_LAYOUT").unwrap())?;
+        layout.set(0, MAP_LAYOUT_VERSION, 0)?;
+        Ok(())
+    }
+
+    /// Attach the XDP program and pin its link. When a previous run pinned one, its program
+    /// is replaced atomically through the link so no packet goes unfiltered, and true is
+    /// returned since the link keeps the flags it was attached with
+    pub fn attach_xdp(
+        &self,
+        program: &mut Xdp,
+        iface: &str,
+        flags: XdpFlags,
+    ) -> Result<bool, anyhow::Error> {
+        let Some(path) = &self.path else {
+            program.attach(iface, flags)?;
+            return Ok(false);
+        };
+        let link_path = path.join(format!("{}{}", XDP_LINK_PREFIX, iface));
+        if link_path.exists() {
+            let link: FdLink = PinnedLink::from_pin(&link_path)
+                .with_context(|| format!("failed to open pinned link {}", link_path.display()))?
+                .into();
+            let link = XdpLink::try_from(link)?;
+            // the pin keeps the link, the program is swapped without detaching
+            program.attach_to_link(link)?;
+            info!("replaced the program of {}", link_path.display());
+            return Ok(true);
+        }
+        let link_id = program.attach(iface, flags)?;
+        let link: FdLink = program
+            .take_link(link_id)?
+            .try_into()
+            .context("the kernel doesn't support XDP links, pinning needs 5.9 or later")?;
+        link.pin(&link_path)
+            .with_context(|| format!("failed to pin link {}", link_path.display()))?;
+        Ok(false)
+    }
+
+    /// Remove the XDP link a previous run pinned for an interface now filtered by TC
+    pub fn unpin_xdp(&self, iface: &str) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let link_path = path.join(format!("{}{}", XDP_LINK_PREFIX, iface));
+        if !link_path.exists() {
+            return;
+        }
+        // the pin was the last reference, the program is detached with it
+        match std::fs::remove_file(&link_path) {
+            Ok(()) => info!("removed the XDP link of {}", iface),
+            Err(e) => warn!("remove {} error {}", link_path.display(), e),
+        }
+    }
+
+    /// Attach a classifier. TC filters have no link to pin, a persistent run adds its filter
+    /// next to the one of the previous run before removing it, and leaves its own attached
+    /// on exit
+    pub fn attach_classifier(
+        &self,
+        program: &mut SchedClassifier,
+        iface: &str,
+        attach_type: TcAttachType,
+    ) -> Result<(), anyhow::Error> {
+        if self.path.is_none() {
+            program.attach(iface, attach_type)?;
+            return Ok(());
+        }
+        let mut attached = None;
+        for (i, priority) in TC_PRIORITIES.into_iter().enumerate() {
+            let options = TcOptions {
+                priority,
+                handle: TC_HANDLE,
+            };
+            // the priority of the previous filter is taken
+            if let Ok(link_id) = program.attach_with_options(iface, attach_type, options) {
+                attached = Some((link_id, TC_PRIORITIES[1 - i]));
+                break;
+            }
+        }
+        let Some((link_id, previous)) = attached else {
+            bail!(
+                "failed to attach the {:?} classifier to {}",
+                attach_type,
+                iface
+            );
+        };
+        // dropping the link would remove the filter
+        std::mem::forget(program.take_link(link_id)?);
+        if let Ok(link) = SchedClassifierLink::attached(iface, attach_type, previous, TC_HANDLE) {
+            if let Err(e) = link.detach() {
+                warn!("remove the previous {:?} filter error {}", attach_type, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove what a previous run left on interfaces that aren't filtered anymore: their
+    /// pinned XDP link, and the classifiers at the sdf priorities of any other interface
+    pub fn remove_stale(&self, interfaces: &[String]) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        for entry in std::fs::read_dir(path).into_iter().flatten().flatten() {
+            let name = entry.file_name();
+            let Some(iface) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(XDP_LINK_PREFIX))
+            else {
+                continue;
+            };
+            if interfaces.iter().any(|name| name == iface) {
+                continue;
+            }
+            match std::fs::remove_file(entry.path()) {
+                Ok(()) => info!(
+                    "removed the XDP link of {}, it isn't filtered anymore",
+                    iface
+                ),
+                Err(e) => warn!("remove {} error {}", entry.path().display(), e),
+            }
+        }
+        for iface in host_interfaces() {
+            if !interfaces.contains(&iface) {
+                remove_filters(&iface);
+            }
+        }
+    }
+
+    /// Undo a persistent run for `--detach`: remove the classifiers of every interface, and
+    /// the pins, which detaches the XDP programs and frees the maps
+    pub fn detach_all(&self) -> Result<(), anyhow::Error> {
+        let Some(path) = &self.path else {
+            bail!("--detach needs the --pin directory");
+        };
+        for iface in host_interfaces() {
+            remove_filters(&iface);
+        }
+        std::fs::remove_dir_all(path)
+            .with_context(|| format!("failed to remove pin directory {}", path.display()))?;
+        info!("removed {}", path.display());
+        Ok(())
+    }
+
+    /// Remove the classifiers a previous run left, when the interface moved back to XDP
+    pub fn detach_classifier(&self, iface: &str, attach_type: TcAttachType) {
+        if self.path.is_none() {
+            return;
+        }
+        for priority in TC_PRIORITIES {
+            if let Ok(link) = SchedClassifierLink::attached(iface, attach_type, priority, TC_HANDLE)
+            {
+                match link.detach() {
+                    Ok(()) => info!("removed the previous {:?} filter of {}", attach_type, iface),
+                    Err(e) => warn!("remove the previous {:?} filter error {}", attach_type, e),
+                }
+            }
+        }
+    }
+}
+
+/// Remove the classifiers attached with the sdf priorities and handle, the filters of other
+/// tools use other ones
+fn remove_filters(iface: &str) {
+    for attach_type in [TcAttachType::Ingress, TcAttachType::Egress] {
+        for priority in TC_PRIORITIES {
+            // constructing the link doesn't check the filter exists, detaching does
+            let Ok(link) = SchedClassifierLink::attached(iface, attach_type, priority, TC_HANDLE)
+            else {
+                continue;
+            };
+            if link.detach().is_ok() {
+                info!("removed the {:?} filter of {}", attach_type, iface);
+            }
+        }
+    }
+}
+
+fn host_interfaces() -> Vec<String> {
+    std::fs::read_dir("/sys/class/net")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect()
+}
@@ -0,0 +1,42 @@
+use aya::{maps::Array, Bpf};
+use sdf_common::{ScanAction, ScanConfig, SCAN_BITMAP_BITS};
+
+use crate::config::{ScanActionConfig, ScanDetectionConfig};
+
+pub fn validate(config: &ScanDetectionConfig) -> Result<(), String> {
+    if config.max_ports > SCAN_BITMAP_BITS || config.max_hosts > SCAN_BITMAP_BITS {
+        return Err(format!(
+            "scan_detection thresholds can't be above {}",
+            SCAN_BITMAP_BITS
+        ));
+    }
+    if config.window.is_zero() {
+        return Err("scan_detection window can't be 0".to_string());
+    }
+    Ok(())
+}
+
+/// Write the scan detector settings to SCAN_CONFIG, None disables it
+pub fn apply_config(bpf: &mut Bpf, config: Option<&ScanDetectionConfig>) -> Result<(), String> {
+    let value = match config {
+        Some(config) => {
+            validate(config)?;
+            ScanConfig {
+                window_ns: config.window.as_nanos() as u64,
+                block_ns: config.block.as_nanos() as u64,
+                max_ports: config.max_ports,
+                max_hosts: config.max_hosts,
+                action: match config.action {
+                    ScanActionConfig::Report => ScanAction::Report as u8,
+                    ScanActionConfig::Drop => ScanAction::Drop as u8,
+                },
+                _padding: [0; 7],
+            }
+        }
+        None => ScanConfig::default(),
+    };
+
+    let mut scan_config: Array<_, ScanConfig> =
+        Array::try_from(bpf.map_mut("SCAN_CONFIG").unwrap()).map_err(|e| e.to_string())?;
+    scan_config.set(0, value, 0).map_err(|e| e.to_string())
+}
@@ -0,0 +1,263 @@
+use std::{
+    cmp::Reverse, collections::HashMap as StdHashMap, net::Ipv4Addr, time::Duration,
+    time::SystemTime,
+};
+
+use aya::{
+    maps::{HashMap, MapError, PerCpuArray, PerCpuHashMap, PerCpuValues},
+    util::nr_cpus,
+    Bpf,
+};
+use log::{error, info, warn};
+use poem_openapi::{Enum, Object};
+use sdf_common::{DropReason, IfaceKey, PACKET_STATS_EVENTS_SUPPRESSED, PACKET_STATS_PASSED};
+
+use crate::{config::MapCapacities, http::ApiError};
+
+/// How often the main loop snapshots the counters into the history
+pub const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Packets seen by the ingress program since it was loaded or reset
+#[derive(Object, Debug, Default, Clone)]
+pub struct PacketStats {
+    pub passed: u64,
+    pub dropped: u64,
+    /// Dropped packets per `DropReason`, like `port_blacklist`
+    pub dropped_by_reason: StdHashMap<String, u64>,
+    /// Times a source crossed a scan detector threshold
+    pub scans_detected: u64,
+    /// Drop events the datapath didn't send, over its rate limit
+    pub events_suppressed: u64,
+}
+
+/// Packets seen on one of the attached interfaces
+#[derive(Object, Debug, Clone)]
+pub struct InterfaceStats {
+    pub interface: String,
+    pub ifindex: u32,
+    pub packets: PacketStats,
+}
+
+/// Every counter read at the same time
+#[derive(Object, Debug, Clone)]
+pub struct Snapshot {
+    /// RFC 3339 time of the snapshot
+    pub timestamp: String,
+    pub packets: PacketStats,
+    /// Dropped packets per port
+    pub dropped_by_port: StdHashMap<u16, u64>,
+}
+
+/// Entries of a map against its capacity
+#[derive(Object, Debug, Clone)]
+pub struct MapUsage {
+    pub name: String,
+    pub entries: u32,
+    pub max_entries: u32,
+    pub level: MapLevel,
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+#[oai(rename_all = "snake_case")]
+pub enum MapLevel {
+    Ok,
+    /// Above `warn_percent` of the capacity
+    Warning,
+    Full,
+}
+
+impl MapLevel {
+    fn of(entries: u32, max_entries: u32, warn_percent: u8) -> Self {
+        if entries >= max_entries {
+            MapLevel::Full
+        } else if entries as u64 * 100 >= max_entries as u64 * warn_percent as u64 {
+            MapLevel::Warning
+        } else {
+            MapLevel::Ok
+        }
+    }
+}
+
+#[derive(Object, Debug)]
+pub struct SourceStats {
+    pub source: String,
+    pub dropped: u64,
+}
+
+/// Sum the per cpu PACKET_STATS counters
+pub fn packet_stats(bpf: &Bpf) -> Result<PacketStats, MapError> {
+    let counters: PerCpuArray<_, u64> = PerCpuArray::try_from(bpf.map("PACKET_STATS").unwrap())?;
+    sum_packets(|index| Ok(counters.get(&index, 0)?.iter().sum()))
+}
+
+/// Sum the per cpu IFACE_STATS counters of each interface
+pub fn interface_stats(
+    bpf: &Bpf,
+    interfaces: &[(String, u32)],
+) -> Result<Vec<InterfaceStats>, MapError> {
+    let counters: PerCpuHashMap<_, IfaceKey, u64> =
+        PerCpuHashMap::try_from(bpf.map("IFACE_STATS").unwrap())?;
+    let mut stats = vec![];
+    for (name, ifindex) in interfaces {
+        // an interface without traffic of a kind has no counter yet
+        let sum = |index| match counters.get(&IfaceKey::new(*ifindex, index), 0) {
+            Ok(values) => Ok(values.iter().sum()),
+            Err(MapError::KeyNotFound) => Ok(0),
+            Err(e) => Err(e),
+        };
+        stats.push(InterfaceStats {
+            interface: name.clone(),
+            ifindex: *ifindex,
+            packets: sum_packets(sum)?,
+        });
+    }
+    Ok(stats)
+}
+
+fn sum_packets(sum: impl Fn(u32) -> Result<u64, MapError>) -> Result<PacketStats, MapError> {
+    let mut stats = PacketStats {
+        passed: sum(PACKET_STATS_PASSED)?,
+        scans_detected: sum(DropReason::ScanDetected as u32)?,
+        events_suppressed: sum(PACKET_STATS_EVENTS_SUPPRESSED)?,
+        ..Default::default()
+    };
+    for reason in DropReason::DROPS {
+        let dropped = sum(reason as u32)?;
+        stats.dropped += dropped;
+        stats
+            .dropped_by_reason
+            .insert(reason.as_str().to_string(), dropped);
+    }
+    Ok(stats)
+}
+
+/// Sources with the most dropped packets, the datapath evicts the least recently dropped ones
+pub fn top_sources(bpf: &Bpf, limit: usize) -> Result<Vec<SourceStats>, MapError> {
+    let sources: HashMap<_, u32, u64> = HashMap::try_from(bpf.map("SOURCE_STATS").unwrap())?;
+    let mut rows: Vec<(u32, u64)> = sources.iter().flatten().collect();
+    rows.sort_by_key(|(_, dropped)| Reverse(*dropped));
+    Ok(rows
+        .into_iter()
+        .take(limit)
+        .map(|(source, dropped)| SourceStats {
+            source: Ipv4Addr::from(source).to_string(),
+            dropped,
+        })
+        .collect())
+}
+
+/// Dropped packets per port, from BLOCKED_STATS
+pub fn blocked_stats(bpf: &Bpf) -> Result<StdHashMap<u16, u64>, MapError> {
+    let blocked: HashMap<_, u16, u64> = HashMap::try_from(bpf.map("BLOCKED_STATS").unwrap())?;
+    Ok(blocked.iter().flatten().collect())
+}
+
+pub fn snapshot(bpf: &Bpf) -> Result<Snapshot, MapError> {
+    Ok(Snapshot {
+        timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        packets: packet_stats(bpf)?,
+        dropped_by_port: blocked_stats(bpf)?,
+    })
+}
+
+/// Entries of one map, LRU maps are counted like the others
+pub fn map_entries(bpf: &Bpf, name: &str) -> Result<usize, MapError> {
+    Ok(if name.starts_with("IFACE_") {
+        let map: HashMap<_, IfaceKey, u8> = HashMap::try_from(bpf.map(name).unwrap())?;
+        map.keys().count()
+    } else if name == "PORT_BLACKLIST" {
+        let map: HashMap<_, u16, u8> = HashMap::try_from(bpf.map(name).unwrap())?;
+        map.keys().count()
+    } else {
+        let map: HashMap<_, u32, u8> = HashMap::try_from(bpf.map(name).unwrap())?;
+        map.keys().count()
+    })
+}
+
+pub fn map_usage(bpf: &Bpf, capacities: &MapCapacities) -> Result<Vec<MapUsage>, MapError> {
+    let mut usage = vec![];
+    for (name, max_entries) in capacities.maps() {
+        let entries = map_entries(bpf, name)? as u32;
+        usage.push(MapUsage {
+            name: name.to_string(),
+            entries,
+            max_entries,
+            level: MapLevel::of(entries, max_entries, capacities.warn_percent),
+        });
+    }
+    Ok(usage)
+}
+
+/// Error of a failed insert into a rule map, MAP_FULL when it has no room left
+pub fn insert_error(bpf: &Bpf, name: &str, capacities: &MapCapacities) -> ApiError {
+    match map_entries(bpf, name) {
+        Ok(entries) if entries >= capacities.of(name) as usize => ApiError::MapFull,
+        _ => ApiError::CannotAddToMap,
+    }
+}
+
+/// Log a map once when it starts filling up, becomes full or goes back to normal
+#[derive(Default)]
+pub struct MapAlerts {
+    levels: StdHashMap<String, MapLevel>,
+}
+
+impl MapAlerts {
+    pub fn update(&mut self, usage: &[MapUsage]) {
+        for map in usage {
+            let previous = self
+                .levels
+                .insert(map.name.clone(), map.level)
+                .unwrap_or(MapLevel::Ok);
+            if previous == map.level {
+                continue;
+            }
+            match map.level {
+                MapLevel::Ok => info!(
+                    "{} back to {} of {} entries",
+                    map.name, map.entries, map.max_entries
+                ),
+                MapLevel::Warning => warn!(
+                    "{} is filling up, {} of {} entries",
+                    map.name, map.entries, map.max_entries
+                ),
+                MapLevel::Full => error!(
+                    "{} is full with {} entries, new entries are rejected or evict the oldest",
+                    map.name, map.entries
+                ),
+            }
+        }
+    }
+}
+
+/// Zero PACKET_STATS and empty IFACE_STATS, BLOCKED_STATS and SOURCE_STATS
+pub fn reset(bpf: &mut Bpf) -> Result<(), anyhow::Error> {
+    let mut counters: PerCpuArray<_, u64> =
+        PerCpuArray::try_from(bpf.map_mut("PACKET_STATS").unwrap())?;
+    let cpus = nr_cpus()?;
+    for index in 0..counters.len() {
+        counters.set(index, PerCpuValues::try_from(vec![0u64; cpus])?, 0)?;
+    }
+
+    let mut interfaces: PerCpuHashMap<_, IfaceKey, u64> =
+        PerCpuHashMap::try_from(bpf.map_mut("IFACE_STATS").unwrap())?;
+    let keys = interfaces.keys().collect::<Result<Vec<_>, _>>()?;
+    for key in keys {
+        interfaces.remove(&key)?;
+    }
+
+    let mut blocked: HashMap<_, u16, u64> =
+        HashMap::try_from(bpf.map_mut("BLOCKED_STATS").unwrap())?;
+    let ports = blocked.keys().collect::<Result<Vec<_>, _>>()?;
+    for port in ports {
+        blocked.remove(&port)?;
+    }
+
+    let mut sources: HashMap<_, u32, u64> =
+        HashMap::try_from(bpf.map_mut("SOURCE_STATS").unwrap())?;
+    let ips = sources.keys().collect::<Result<Vec<_>, _>>()?;
+    for ip in ips {
+        sources.remove(&ip)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use std::{
+    net::Ipv4Addr,
+    time::{Duration, SystemTime},
+};
+
+use aya::{
+    maps::{HashMap, MapError},
+    Bpf,
+};
+use poem_openapi::Object;
+
+use crate::{config::TrapConfig, http::ApiError, utils};
+
+/// Trap ports and the sources they banned
+#[derive(Object, Debug, Default)]
+pub struct TrapList {
+    pub ports: Vec<u16>,
+    pub bans: Vec<TrapBanInfo>,
+}
+
+#[derive(Object, Debug)]
+pub struct TrapBanInfo {
+    pub source: String,
+    /// RFC 3339 time at which the datapath lets the source in again
+    pub expires_at: String,
+}
+
+/// Clock of `bpf_ktime_get_ns`, used by the datapath for ban expiry
+fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Replace the content of TRAP_PORTS, None removes every trap port
+pub fn apply_config(bpf: &mut Bpf, config: Option<&TrapConfig>) -> Result<(), String> {
+    let mut trap_ports: HashMap<_, u16, u64> =
+        HashMap::try_from(bpf.map_mut("TRAP_PORTS").unwrap()).map_err(|e| e.to_string())?;
+    let keys = trap_ports
+        .keys()
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    for port in keys {
+        trap_ports.remove(&port).map_err(|e| e.to_string())?;
+    }
+
+    if let Some(config) = config {
+        let ttl = config.ban_ttl.as_nanos() as u64;
+        for port in &config.ports {
+            trap_ports
+                .insert(*port, ttl, 0)
+                .map_err(|e| format!("add trap port {} error {}", port, e))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn list(bpf: &Bpf) -> Result<TrapList, MapError> {
+    let trap_ports: HashMap<_, u16, u64> = HashMap::try_from(bpf.map("TRAP_PORTS").unwrap())?;
+    let trap_bans: HashMap<_, u32, u64> = HashMap::try_from(bpf.map("TRAP_BANS").unwrap())?;
+
+    // expired bans are only removed when the source comes back
+    let now = monotonic_ns();
+    let wall_now = SystemTime::now();
+    Ok(TrapList {
+        ports: trap_ports.keys().flatten().collect(),
+        bans: trap_bans
+            .iter()
+            .flatten()
+            .filter(|(_, expires)| *expires > now)
+            .map(|(ip, expires)| TrapBanInfo {
+                source: Ipv4Addr::from(ip).to_string(),
+                expires_at: humantime::format_rfc3339_seconds(
+                    wall_now + Duration::from_nanos(expires - now),
+                )
+                .to_string(),
+            })
+            .collect(),
+    })
+}
+
+/// Let a source banned by a trap port in before its ban expires
+pub fn lift(bpf: &mut Bpf, ip: &str) -> Result<String, ApiError> {
+    let addr = utils::to_ipv4(ip).ok_or(ApiError::OnlySupportIpV4)?;
+    let mut trap_bans: HashMap<_, u32, u64> = HashMap::try_from(bpf.map_mut("TRAP_BANS").unwrap())
+        .map_err(|_| ApiError::CannotUpdateMap)?;
+    trap_bans.remove(&addr).map_err(|_| ApiError::IpNotFound)?;
+    Ok("REMOVED".to_string())
+}
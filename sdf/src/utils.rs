@@ -11,3 +11,44 @@ pub fn to_ipv4(ip: &str) -> Option<u32> {
         Err(e) => None,
     }
 }
+
+/// Index of a network interface, None when it doesn't exist
+pub fn ifindex(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+/// Types of /sys/class/net/<name>/type whose packets start at the IP header: ARPHRD_PPP,
+/// ARPHRD_RAWIP, ARPHRD_TUNNEL, ARPHRD_TUNNEL6, ARPHRD_SIT, ARPHRD_IPGRE, ARPHRD_IP6GRE and
+/// ARPHRD_NONE of tun and wireguard. Loopback and gretap have an Ethernet header
+const L3_TYPES: [u16; 8] = [512, 519, 768, 769, 776, 778, 823, 65534];
+
+/// An interface whose type can't be read is assumed to be Ethernet
+pub fn has_ethernet(name: &str) -> bool {
+    match std::fs::read_to_string(format!("/sys/class/net/{}/type", name)) {
+        Ok(kind) => !is_l3_type(&kind),
+        Err(_) => true,
+    }
+}
+
+fn is_l3_type(kind: &str) -> bool {
+    matches!(kind.trim().parse::<u16>(), Ok(kind) if L3_TYPES.contains(&kind))
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_l3_type;
+
+    #[test]
+    fn l3_types() {
+        for kind in ["65534\n", "778\n", "512"] {
+            assert!(is_l3_type(kind), "{}", kind);
+        }
+        for kind in ["1\n", "772\n", "32\n", "invalid"] {
+            assert!(!is_l3_type(kind), "{}", kind);
+        }
+    }
+}
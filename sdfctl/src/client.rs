@@ -0,0 +1,161 @@
+use std::{fmt, path::Path};
+
+use reqwest::{
+    blocking::{Client as HttpClient, RequestBuilder},
+    Certificate, Identity, Method,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Settings read from the config file, every field can be overridden from the command line or env
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    /// Control API address, like `https://10.0.0.1:3000`
+    pub endpoint: Option<String>,
+    /// Bearer token declared in the daemon `api_tokens`
+    pub token: Option<String>,
+    /// PEM CA used to verify the daemon certificate
+    pub ca_cert: Option<String>,
+    /// PEM client certificate for mTLS
+    pub client_cert: Option<String>,
+    /// PEM private key of the client certificate
+    pub client_key: Option<String>,
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Self, ClientError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| ClientError::Config(format!("read {:?} error {}", path, e)))?;
+        serde_yaml::from_str(&content)
+            .map_err(|e| ClientError::Config(format!("parse {:?} error {}", path, e)))
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// Invalid config, certificates or arguments
+    Config(String),
+    /// The daemon could not be reached
+    Unreachable(reqwest::Error),
+    /// The daemon answered with `status: false`
+    Api { status: u16, error: String },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Config(e) => write!(f, "invalid config: {}", e),
+            ClientError::Unreachable(e) => write!(f, "cannot reach sdf: {}", e),
+            ClientError::Api { status, error } => write!(f, "sdf answered {}: {}", status, error),
+        }
+    }
+}
+
+/// Percent-encode a value to use it as one segment of a route path. `.` and `..` would be
+/// resolved by the url parser and are refused
+pub fn path_segment(value: &str) -> Result<String, ClientError> {
+    if value.is_empty() || value == "." || value == ".." {
+        return Err(ClientError::Config(format!(
+            "{:?} is not a valid value",
+            value
+        )));
+    }
+    let mut segment = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                segment.push(byte as char)
+            }
+            _ => segment.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    Ok(segment)
+}
+
+/// Same envelope as the daemon `ApiResult`
+#[derive(Deserialize)]
+struct ApiResult {
+    status: bool,
+    error: Option<String>,
+    data: Option<Value>,
+}
+
+pub struct Client {
+    http: HttpClient,
+    endpoint: String,
+    token: Option<String>,
+}
+
+impl Client {
+    pub fn new(config: Config) -> Result<Self, ClientError> {
+        let read = |path: &str| {
+            std::fs::read(path)
+                .map_err(|e| ClientError::Config(format!("read {} error {}", path, e)))
+        };
+
+        let mut builder = HttpClient::builder();
+        if let Some(ca) = &config.ca_cert {
+            let ca = Certificate::from_pem(&read(ca)?)
+                .map_err(|e| ClientError::Config(format!("invalid ca certificate {}", e)))?;
+            builder = builder.add_root_certificate(ca);
+        }
+        match (&config.client_cert, &config.client_key) {
+            (Some(cert), Some(key)) => {
+                let mut pem = read(cert)?;
+                pem.extend(read(key)?);
+                let identity = Identity::from_pem(&pem).map_err(|e| {
+                    ClientError::Config(format!("invalid client certificate {}", e))
+                })?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => {
+                return Err(ClientError::Config(
+                    "client_cert and client_key must be set together".to_string(),
+                ))
+            }
+        }
+
+        Ok(Self {
+            http: builder
+                .build()
+                .map_err(|e| ClientError::Config(e.to_string()))?,
+            endpoint: config
+                .endpoint
+                .unwrap_or_else(|| "http://127.0.0.1:3000".to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            token: config.token,
+        })
+    }
+
+    /// Call a `/v1` route and return the `data` of the envelope
+    pub fn call(&self, method: Method, path: &str) -> Result<Value, ClientError> {
+        self.send(
+            self.http
+                .request(method, format!("{}/v1/{}", self.endpoint, path)),
+        )
+    }
+
+    fn send(&self, mut req: RequestBuilder) -> Result<Value, ClientError> {
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token);
+        }
+
+        let res = req.send().map_err(ClientError::Unreachable)?;
+        let status = res.status().as_u16();
+        let body: ApiResult = res.json().map_err(|e| ClientError::Api {
+            status,
+            error: format!("invalid response {}", e),
+        })?;
+
+        if body.status {
+            Ok(body.data.unwrap_or(Value::Null))
+        } else {
+            Err(ClientError::Api {
+                status,
+                error: body.error.unwrap_or_default(),
+            })
+        }
+    }
+}
@@ -0,0 +1,322 @@
+mod client;
+mod output;
+
+use std::{path::PathBuf, process::exit, thread::sleep, time::Duration};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use reqwest::Method;
+
+use client::{path_segment, Client, ClientError, Config};
+use output::{cell, print_json, print_table, Format};
+
+/// The daemon refused the request, like an unknown rule or an invalid address
+const EXIT_API_ERROR: i32 = 1;
+/// Invalid config file, certificates or arguments
+const EXIT_CONFIG_ERROR: i32 = 2;
+/// The daemon could not be reached
+const EXIT_UNREACHABLE: i32 = 3;
+/// How often `events --follow` polls for new events
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Parser)]
+pub struct Options {
+    /// YAML file with endpoint, token and tls settings
+    #[clap(long, env = "SDFCTL_CONFIG")]
+    config: Option<PathBuf>,
+    /// Control API address, like http://127.0.0.1:3000
+    #[clap(long, env = "SDF_ENDPOINT")]
+    endpoint: Option<String>,
+    /// Bearer token declared in the daemon api_tokens
+    #[clap(long, env = "SDF_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// Output format
+    #[clap(short, long, value_enum, default_value = "table")]
+    output: Format,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Manage rules
+    #[clap(subcommand)]
+    Rules(RulesCommand),
+    /// Reload rules from the daemon config file
+    Reload,
+    /// Dropped packets per port
+    Stats,
+    /// Interfaces sdf is attached to, with their datapath
+    Interfaces,
+    /// Entries of the eBPF maps against their capacity
+    Maps,
+    /// Sources with the most dropped packets
+    Top {
+        #[clap(long, default_value = "20")]
+        limit: usize,
+    },
+    /// Recent rule changes
+    Audit {
+        #[clap(long, default_value = "20")]
+        limit: usize,
+    },
+    /// Recent drop events
+    Events {
+        #[clap(long, default_value = "20")]
+        limit: usize,
+        /// Keep printing new events as they arrive
+        #[clap(short, long)]
+        follow: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum RulesCommand {
+    /// List the rules currently enforced
+    List,
+    /// Add a rule
+    Add { kind: RuleKind, value: String },
+    /// Remove a rule
+    Remove { kind: RuleKind, value: String },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum RuleKind {
+    SourceBlacklist,
+    SourceWhitelist,
+    PortBlacklist,
+}
+
+impl RuleKind {
+    fn path(&self) -> &'static str {
+        match self {
+            RuleKind::SourceBlacklist => "rules/blacklist/source",
+            RuleKind::SourceWhitelist => "rules/whitelist/source",
+            RuleKind::PortBlacklist => "rules/blacklist/port",
+        }
+    }
+}
+
+fn load_config(opts: &Options) -> Result<Config, ClientError> {
+    let mut config = match &opts.config {
+        Some(path) => Config::from_file(path)?,
+        None => Config::default(),
+    };
+    if opts.endpoint.is_some() {
+        config.endpoint = opts.endpoint.clone();
+    }
+    if opts.token.is_some() {
+        config.token = opts.token.clone();
+    }
+    Ok(config)
+}
+
+/// Print drop events, one line each so that `--follow` can keep appending
+fn tail_events(
+    client: &Client,
+    format: Format,
+    limit: usize,
+    follow: bool,
+) -> Result<(), ClientError> {
+    let mut after = 0;
+    loop {
+        let data = client.call(
+            Method::GET,
+            &format!("events?after={}&limit={}", after, limit),
+        )?;
+        for event in data.as_array().into_iter().flatten() {
+            after = after.max(event["seq"].as_u64().unwrap_or_default());
+            match format {
+                Format::Json => println!("{}", event),
+                Format::Table => println!(
+                    "{}  {:>15}:{:<5} -> {:>15}:{:<5}  {} {}",
+                    cell(&event["timestamp"]),
+                    cell(&event["source"]),
+                    cell(&event["source_port"]),
+                    cell(&event["dest"]),
+                    cell(&event["dest_port"]),
+                    cell(&event["reason"]),
+                    cell(&event["matched"]),
+                ),
+            }
+        }
+
+        if !follow {
+            return Ok(());
+        }
+        sleep(FOLLOW_INTERVAL);
+    }
+}
+
+fn run(opts: Options) -> Result<(), ClientError> {
+    let client = Client::new(load_config(&opts)?)?;
+
+    use Command::*;
+    if let Events { limit, follow } = &opts.command {
+        return tail_events(&client, opts.output, *limit, *follow);
+    }
+
+    let data = match &opts.command {
+        Rules(RulesCommand::List) => client.call(Method::GET, "rules")?,
+        Rules(RulesCommand::Add { kind, value }) => client.call(
+            Method::PUT,
+            &format!("{}/{}", kind.path(), path_segment(value)?),
+        )?,
+        Rules(RulesCommand::Remove { kind, value }) => client.call(
+            Method::DELETE,
+            &format!("{}/{}", kind.path(), path_segment(value)?),
+        )?,
+        Reload => client.call(Method::POST, "rules/reload")?,
+        Stats => client.call(Method::GET, "stats/blocked")?,
+        Interfaces => client.call(Method::GET, "interfaces")?,
+        Maps => client.call(Method::GET, "stats/maps")?,
+        Top { limit } => client.call(Method::GET, &format!("stats/sources?limit={}", limit))?,
+        Audit { limit } => client.call(Method::GET, &format!("audit?limit={}", limit))?,
+        Events { .. } => unreachable!("handled by tail_events"),
+    };
+
+    if let Format::Json = opts.output {
+        print_json(&data);
+        return Ok(());
+    }
+
+    match &opts.command {
+        Rules(RulesCommand::List) => {
+            // addresses resolved from dns names are also in source_whitelist
+            let dns: Vec<_> = data["dns_whitelist"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .collect();
+            let mut rows = vec![];
+            for (kind, key) in [
+                ("source-blacklist", "source_blacklist"),
+                ("source-whitelist", "source_whitelist"),
+                ("port-blacklist", "port_blacklist"),
+                ("auto-whitelist", "auto_whitelist"),
+                ("dns-whitelist", "dns_whitelist"),
+            ] {
+                for value in data[key].as_array().into_iter().flatten() {
+                    if key == "source_whitelist" && dns.contains(&value) {
+                        continue;
+                    }
+                    rows.push(vec![kind.to_string(), cell(value), "-".to_string()]);
+                }
+            }
+            for rules in data["interfaces"].as_array().into_iter().flatten() {
+                for (kind, key) in [
+                    ("source-blacklist", "source_blacklist"),
+                    ("source-whitelist", "source_whitelist"),
+                    ("port-blacklist", "port_blacklist"),
+                ] {
+                    for value in rules[key].as_array().into_iter().flatten() {
+                        rows.push(vec![
+                            kind.to_string(),
+                            cell(value),
+                            cell(&rules["interface"]),
+                        ]);
+                    }
+                }
+            }
+            print_table(&["KIND", "VALUE", "INTERFACE"], &rows);
+        }
+        Stats => {
+            let mut rows: Vec<(String, u64)> = data
+                .as_object()
+                .into_iter()
+                .flatten()
+                .map(|(port, count)| (port.clone(), count.as_u64().unwrap_or_default()))
+                .collect();
+            rows.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+            let rows: Vec<_> = rows
+                .into_iter()
+                .map(|(port, count)| vec![port, count.to_string()])
+                .collect();
+            print_table(&["PORT", "DROPPED"], &rows);
+        }
+        Interfaces => {
+            let rows: Vec<_> = data
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|iface| {
+                    vec![
+                        cell(&iface["interface"]),
+                        cell(&iface["ifindex"]),
+                        cell(&iface["datapath"]),
+                        cell(&iface["xdp_mode"]),
+                        cell(&iface["xdp_chain"]),
+                    ]
+                })
+                .collect();
+            print_table(
+                &["INTERFACE", "IFINDEX", "DATAPATH", "XDP MODE", "CHAIN"],
+                &rows,
+            );
+        }
+        Maps => {
+            let rows: Vec<_> = data
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|map| {
+                    vec![
+                        cell(&map["name"]),
+                        cell(&map["entries"]),
+                        cell(&map["max_entries"]),
+                        cell(&map["level"]),
+                    ]
+                })
+                .collect();
+            print_table(&["MAP", "ENTRIES", "MAX", "LEVEL"], &rows);
+        }
+        Top { .. } => {
+            let rows: Vec<_> = data
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|row| vec![cell(&row["source"]), cell(&row["dropped"])])
+                .collect();
+            print_table(&["SOURCE", "DROPPED"], &rows);
+        }
+        Audit { .. } => {
+            let rows: Vec<_> = data
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|record| {
+                    let result = match record["success"].as_bool() {
+                        Some(true) => "ok".to_string(),
+                        _ => cell(&record["error"]),
+                    };
+                    vec![
+                        cell(&record["timestamp"]),
+                        cell(&record["caller"]),
+                        cell(&record["remote_addr"]),
+                        cell(&record["action"]),
+                        cell(&record["rule"]),
+                        result,
+                    ]
+                })
+                .collect();
+            print_table(
+                &["TIME", "CALLER", "REMOTE", "ACTION", "RULE", "RESULT"],
+                &rows,
+            );
+        }
+        _ => println!("{}", cell(&data)),
+    }
+    Ok(())
+}
+
+fn main() {
+    let opts = Options::parse();
+
+    if let Err(e) = run(opts) {
+        eprintln!("{e}");
+        exit(match e {
+            ClientError::Api { .. } => EXIT_API_ERROR,
+            ClientError::Config(_) => EXIT_CONFIG_ERROR,
+            ClientError::Unreachable(_) => EXIT_UNREACHABLE,
+        });
+    }
+}